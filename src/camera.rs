@@ -1,48 +1,305 @@
-use bevy::prelude::*;
+use std::f32::consts::{PI, TAU};
 
-use crate::{gamepad::Inputs};
+use bevy::{prelude::*, core_pipeline::clear_color::ClearColorConfig, render::camera::Viewport, window::WindowResized};
+use bevy_rapier3d::prelude::*;
+
+use crate::{camera_rail::CameraRailPlayback, character_controller::{CharacterController, CharacterControllerLabel}, gamepad::Inputs, player::{ground_query_filter, Player}, player_slots::{PlayerJoined, PlayerLeft, PlayerSlot}, spectator::SpectatorCamera};
 
 pub struct CameraPlugin;
 
+/// Tuning shared by every player camera. Angles are in radians.
+#[derive(Resource, Clone, Debug)]
+pub struct CameraConfig {
+    pub yaw_speed: f32,
+    pub pitch_speed: f32,
+    // Pitch above the horizon, negative looks up at the player from below
+    pub default_pitch: f32,
+    pub min_pitch: f32,
+    pub max_pitch: f32,
+    pub default_distance: f32,
+    pub min_distance: f32,
+    pub max_distance: f32,
+    // Distance change per second at full zoom input
+    pub zoom_speed: f32,
+    // How quickly the camera catches up with the player and with its new angles,
+    // higher is snappier and 0 disables smoothing
+    pub follow_damping: f32,
+    pub rotation_damping: f32,
+    // Radius of the sphere cast along the arm, keeps the near plane out of walls
+    pub collision_radius: f32,
+    // How fast the arm grows back once the view is clear, per second
+    pub arm_ease_out: f32,
+    // Swing behind the player's movement after `recenter_delay` seconds without camera input
+    pub auto_recenter: bool,
+    pub recenter_delay: f32,
+    // Radians per second
+    pub recenter_speed: f32,
+    // Keep the grabbed body, or the closest `LockOnTarget`, in view in front of the player
+    pub lock_on: bool,
+    pub lock_on_range: f32,
+    // How far the view centers from the player towards the target, 0.5 is halfway
+    pub lock_on_focus: f32,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            yaw_speed: 2.,
+            pitch_speed: 1.5,
+            // Same view as the old fixed (-15, 5, 0) offset
+            default_pitch: (5f32 / 15.).atan(),
+            min_pitch: -0.3,
+            max_pitch: 1.2,
+            default_distance: Vec2::new(15., 5.).length(),
+            min_distance: 4.,
+            max_distance: 25.,
+            zoom_speed: 10.,
+            follow_damping: 10.,
+            rotation_damping: 15.,
+            collision_radius: 0.4,
+            arm_ease_out: 4.,
+            auto_recenter: true,
+            recenter_delay: 1.5,
+            recenter_speed: 1.5,
+            lock_on: false,
+            lock_on_range: 30.,
+            lock_on_focus: 0.35,
+        }
+    }
+}
+
+/// A local player's camera, rendering to that player's part of the screen.
 #[derive(Component)]
 pub struct MainCamera {
-    angle: f32
+    // Where the input wants the camera
+    yaw: f32,
+    pitch: f32,
+    distance: f32,
+    // Smoothed towards the values above and the player position
+    smoothed_yaw: f32,
+    smoothed_pitch: f32,
+    focus: Vec3,
+    // Current length of the spring arm, shorter than `distance` when something is in the way
+    arm_length: f32,
+    // Seconds since the last camera input
+    idle_time: f32,
+    pub player: Entity
 }
 
+/// Something the camera can lock on to.
 #[derive(Component)]
-pub struct CameraFollow;
+pub struct LockOnTarget;
 
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
         app
-        .add_startup_system(spawn_camera)
-        .add_system(camera_movement);
+        .init_resource::<CameraConfig>()
+        .add_system(spawn_camera)
+        .add_system(split_screen_layout.after(spawn_camera))
+        // The players move relative to last frame's camera, whatever order the systems would run in
+        .add_system(camera_movement.after(CharacterControllerLabel::Move));
     }
 }
 
 fn spawn_camera(
-    mut commands: Commands
+    mut commands: Commands,
+    config: Res<CameraConfig>,
+    player_query: Query<&Transform>,
+    mut joined: EventReader<PlayerJoined>,
+    mut left: EventReader<PlayerLeft>,
+    camera_query: Query<(Entity, &MainCamera)>
+) {
+    for ev in joined.iter() {
+        commands.spawn(Camera3dBundle {
+            transform: Transform::from_xyz(-15.0, 5.0, 0.0).looking_at(Vec3 { x: 0., y: 1.5, z: 0.}, Vec3::Y),
+            ..default()
+        }).insert(MainCamera {
+            yaw: 0.,
+            pitch: config.default_pitch,
+            distance: config.default_distance,
+            smoothed_yaw: 0.,
+            smoothed_pitch: config.default_pitch,
+            focus: player_query.get(ev.player).map_or(Vec3::ZERO, |transform| transform.translation),
+            arm_length: config.default_distance,
+            idle_time: 0.,
+            player: ev.player
+        });
+    }
+    for ev in left.iter() {
+        for (entity, camera) in camera_query.iter() {
+            if camera.player == ev.player {
+                commands.entity(entity).despawn_recursive();
+            }
+        }
+    }
+}
+
+/// Splits the window between the cameras, in player slot order:
+/// full screen for one player, side by side for two, and quarters for three or four.
+fn split_screen_layout(
+    mut commands: Commands,
+    windows: Res<Windows>,
+    mut resized: EventReader<WindowResized>,
+    added_query: Query<(), Added<MainCamera>>,
+    mut left: EventReader<PlayerLeft>,
+    mut camera_query: Query<(Entity, &MainCamera, &mut Camera, &mut Camera3d)>,
+    slot_query: Query<&PlayerSlot>
 ) {
-    commands.spawn(Camera3dBundle {
-        transform: Transform::from_xyz(-15.0, 5.0, 0.0).looking_at(Vec3 { x: 0., y: 1.5, z: 0.}, Vec3::Y),
-        ..default()
-    }).insert(MainCamera {
-        angle: 0.
-    });
+    let changed = resized.iter().count() > 0 || left.iter().count() > 0 || !added_query.is_empty();
+    if !changed {
+        return;
+    }
+    let window = if let Some(window) = windows.get_primary() { window } else { return };
+    let window_size = UVec2::new(window.physical_width(), window.physical_height());
+
+    let mut cameras: Vec<_> = camera_query.iter_mut()
+        .filter_map(|camera| slot_query.get(camera.1.player).ok().map(|slot| (slot.index, camera)))
+        .collect();
+    cameras.sort_by_key(|(index, _)| *index);
+
+    let count = cameras.len() as u32;
+    let (columns, rows) = match count {
+        0 | 1 => (1, 1),
+        2 => (2, 1),
+        _ => (2, 2),
+    };
+    let size = UVec2::new(window_size.x / columns, window_size.y / rows);
+    for (i, (_, (entity, _, mut camera, mut camera_3d))) in cameras.into_iter().enumerate() {
+        let i = i as u32;
+        camera.viewport = Some(Viewport {
+            physical_position: UVec2::new(i % columns * size.x, i / columns * size.y),
+            physical_size: size,
+            ..default()
+        });
+        camera.priority = i as isize;
+        // The first camera clears the whole window, unused quarters included
+        camera_3d.clear_color = if i == 0 { ClearColorConfig::Default } else { ClearColorConfig::None };
+        // Draw the UI once, over the first player's view
+        commands.entity(entity).insert(UiCameraConfig { show_ui: i == 0 });
+    }
+}
+
+/// Yaw that puts the camera behind the player when looking along `direction`.
+fn yaw_behind(direction: Vec3) -> f32 {
+    (-direction.z).atan2(direction.x)
+}
+
+/// `from` moved towards `to` by at most `max_step`, the short way around.
+fn rotate_towards(from: f32, to: f32, max_step: f32) -> f32 {
+    let difference = (to - from + PI).rem_euclid(TAU) - PI;
+    from + difference.clamp(-max_step, max_step)
+}
+
+/// Exponential smoothing factor for this frame, 1 snaps straight to the target.
+fn damping_factor(damping: f32, delta_seconds: f32) -> f32 {
+    if damping <= 0. { 1. } else { 1. - (-damping * delta_seconds).exp() }
 }
 
-fn camera_movement(
+#[allow(clippy::type_complexity)]
+pub fn camera_movement(
     time: Res<Time>,
-    mut query_camera: Query<(&mut Transform, &mut MainCamera), With<MainCamera>>,
-    query_target: Query<&mut Transform, (With<CameraFollow>, Without<MainCamera>)>,
-    inputs: Res<Inputs>
+    config: Res<CameraConfig>,
+    rapier_context: Res<RapierContext>,
+    // Cameras on a rail or spectating are driven by their own systems
+    mut query_camera: Query<(&mut Transform, &mut MainCamera), (Without<CameraRailPlayback>, Without<SpectatorCamera>)>,
+    query_target: Query<(&Transform, &Inputs, Option<&Player>, Option<&CharacterController>), Without<MainCamera>>,
+    lock_on_query: Query<Entity, With<LockOnTarget>>,
+    global_transforms: Query<&GlobalTransform>
 ) {
-    if let Ok(target_transform) = query_target.get_single() {
-        for (mut camera_transform, mut camera) in query_camera.iter_mut() {
-            camera.angle += inputs.camera_movement.x * time.delta_seconds() * 2.;
-            camera_transform.translation = target_transform.translation + Vec3::new(-15.,5.,0.);
-            camera_transform.rotate_around(target_transform.translation, Quat::from_rotation_y(camera.angle));
-            camera_transform.look_at(target_transform.translation, Vec3::Y);
+    let dt = time.delta_seconds();
+    for (mut camera_transform, mut camera) in query_camera.iter_mut() {
+        if let Ok((target_transform, inputs, player, controller)) = query_target.get(camera.player) {
+            camera.yaw += inputs.camera_movement.x * config.yaw_speed * dt;
+            camera.pitch = (camera.pitch + inputs.camera_movement.y * config.pitch_speed * dt).clamp(config.min_pitch, config.max_pitch);
+            camera.distance = (camera.distance - inputs.camera_zoom * config.zoom_speed * dt).clamp(config.min_distance, config.max_distance);
+
+            let has_input = inputs.camera_movement.length() > 0.01 || inputs.camera_zoom != 0.;
+            camera.idle_time = if has_input { 0. } else { camera.idle_time + dt };
+
+            let player_position = target_transform.translation;
+            let mut focus_target = player_position;
+            let lock_on_position = if config.lock_on {
+                // The grabbed body first, otherwise the closest target in range
+                let grabbed = player.and_then(|player| player.grabbing);
+                let target = grabbed.or_else(|| {
+                    lock_on_query.iter()
+                        .filter(|entity| *entity != camera.player)
+                        .filter_map(|entity| global_transforms.get(entity).ok().map(|transform| (entity, transform.translation())))
+                        .filter(|(_, position)| position.distance(player_position) <= config.lock_on_range)
+                        .min_by(|a, b| a.1.distance(player_position).total_cmp(&b.1.distance(player_position)))
+                        .map(|(entity, _)| entity)
+                });
+                target.and_then(|target| global_transforms.get(target).ok()).map(|transform| transform.translation())
+            } else {
+                None
+            };
+
+            if let Some(lock_on_position) = lock_on_position {
+                let to_target = (lock_on_position - player_position) * Vec3::new(1., 0., 1.);
+                if to_target.length() > 0.01 {
+                    camera.yaw = rotate_towards(camera.yaw, yaw_behind(to_target), TAU);
+                }
+                focus_target = player_position.lerp(lock_on_position, config.lock_on_focus);
+            } else if config.auto_recenter && camera.idle_time >= config.recenter_delay {
+                let velocity = controller.map_or(Vec3::ZERO, |controller| controller.velocity * Vec3::new(1., 0., 1.));
+                if velocity.length() > 0.5 {
+                    camera.yaw = rotate_towards(camera.yaw, yaw_behind(velocity), config.recenter_speed * dt);
+                }
+            }
+
+            let follow = damping_factor(config.follow_damping, dt);
+            let rotation = damping_factor(config.rotation_damping, dt);
+            camera.focus = camera.focus.lerp(focus_target, follow);
+            camera.smoothed_yaw += (camera.yaw - camera.smoothed_yaw) * rotation;
+            camera.smoothed_pitch += (camera.pitch - camera.smoothed_pitch) * rotation;
+
+            let focus = camera.focus;
+            let direction = Quat::from_rotation_y(camera.smoothed_yaw)
+                * Vec3::new(-camera.smoothed_pitch.cos(), camera.smoothed_pitch.sin(), 0.);
+
+            // Spring arm: pull in right away when blocked, ease back out when clear
+            let hit = rapier_context.cast_shape(
+                focus, Quat::IDENTITY, direction, &Collider::ball(config.collision_radius), camera.distance, ground_query_filter(),
+            );
+            let clear_length = hit.map_or(camera.distance, |(_, toi)| toi.toi);
+            camera.arm_length = if clear_length < camera.arm_length {
+                clear_length
+            } else {
+                camera.arm_length + (clear_length - camera.arm_length) * (config.arm_ease_out * dt).min(1.)
+            };
+
+            camera_transform.translation = focus + direction * camera.arm_length;
+            camera_transform.look_at(focus, Vec3::Y);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaw_behind_puts_the_camera_opposite_the_direction() {
+        for direction in [Vec3::X, Vec3::Z, Vec3::new(-1., 0., 2.), Vec3::new(3., 0., -1.)] {
+            // The offset from the focus `camera_movement` uses, without pitch
+            let offset = Quat::from_rotation_y(yaw_behind(direction)) * Vec3::NEG_X;
+            assert!(offset.abs_diff_eq(-direction.normalize(), 1e-5));
+        }
+    }
+
+    #[test]
+    fn rotate_towards_is_limited_by_the_step() {
+        assert!((rotate_towards(0., 1., 0.25) - 0.25).abs() < 1e-5);
+        assert!((rotate_towards(0., -1., 0.25) + 0.25).abs() < 1e-5);
+        // Reaches the target once it is within a step
+        assert!((rotate_towards(0.5, 0.6, 0.25) - 0.6).abs() < 1e-5);
+    }
+
+    #[test]
+    fn rotate_towards_takes_the_short_way_around() {
+        // From just under PI to just over -PI is a small step forward, not almost a full turn back
+        let yaw = rotate_towards(3., -3., 1.);
+        assert!((yaw - (TAU - 3.)).abs() < 1e-5);
+        assert!((rotate_towards(-3., 3., 0.1) - (-3.1)).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,157 @@
+use bevy::prelude::*;
+
+use crate::{camera::MainCamera, character_controller::CharacterControllerLabel, gamepad::Inputs, input_map::Action, spectator::{InputSuspended, SpectatorCamera}};
+
+pub struct CameraRailPlugin;
+
+/// A camera path through `points`, looking at `look_at`, played over `duration` seconds.
+/// The path is a Catmull-Rom spline, so it goes through every point.
+#[derive(Component, Clone)]
+pub struct CameraRail {
+    pub points: Vec<Vec3>,
+    pub look_at: Vec3,
+    pub duration: f32,
+    // Play on every player camera as soon as there is one, e.g. for the level intro
+    pub autoplay: bool,
+}
+
+/// Added to a `MainCamera` while a rail drives it instead of `camera_movement`.
+/// Its player's inputs are suspended meanwhile, jump or pause skip the rail instead.
+#[derive(Component)]
+pub struct CameraRailPlayback {
+    pub rail: Entity,
+    pub elapsed: f32,
+}
+
+pub struct CameraRailStarted {
+    pub rail: Entity,
+}
+
+pub struct CameraRailFinished {
+    pub rail: Entity,
+    pub skipped: bool,
+}
+
+impl Plugin for CameraRailPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .add_event::<CameraRailStarted>()
+        .add_event::<CameraRailFinished>()
+        .add_system(start_camera_rails)
+        .add_system(camera_rail_playback.after(start_camera_rails).after(CharacterControllerLabel::Move));
+    }
+}
+
+impl CameraRail {
+    /// Position along the rail, `t` from 0 to 1.
+    pub fn position(&self, t: f32) -> Vec3 {
+        let count = self.points.len();
+        if count < 2 {
+            return self.points.first().copied().unwrap_or(Vec3::ZERO);
+        }
+        let segments = (count - 1) as f32;
+        let scaled = t.clamp(0., 1.) * segments;
+        let i = (scaled.floor() as usize).min(count - 2);
+        let local = scaled - i as f32;
+
+        // The ends are repeated so the rail starts and stops on the first and last points
+        let point = |index: isize| self.points[index.clamp(0, count as isize - 1) as usize];
+        let i = i as isize;
+        let (p0, p1, p2, p3) = (point(i - 1), point(i), point(i + 1), point(i + 2));
+        let local2 = local * local;
+        let local3 = local2 * local;
+        0.5 * (2. * p1
+            + (p2 - p0) * local
+            + (2. * p0 - 5. * p1 + 4. * p2 - p3) * local2
+            + (3. * p1 - p0 - 3. * p2 + p3) * local3)
+    }
+}
+
+fn start_camera_rails(
+    mut commands: Commands,
+    mut rail_query: Query<(Entity, &mut CameraRail)>,
+    camera_query: Query<(Entity, &MainCamera)>,
+    mut started: EventWriter<CameraRailStarted>,
+) {
+    if camera_query.is_empty() {
+        return;
+    }
+    for (rail_entity, mut rail) in rail_query.iter_mut() {
+        if !rail.autoplay {
+            continue;
+        }
+        rail.autoplay = false;
+        for (entity, camera) in camera_query.iter() {
+            commands.entity(entity).insert(CameraRailPlayback { rail: rail_entity, elapsed: 0. });
+            commands.entity(camera.player).insert(InputSuspended);
+        }
+        started.send(CameraRailStarted { rail: rail_entity });
+    }
+}
+
+fn camera_rail_playback(
+    mut commands: Commands,
+    time: Res<Time>,
+    inputs: Res<Inputs>,
+    rail_query: Query<&CameraRail>,
+    mut camera_query: Query<(Entity, &MainCamera, &mut CameraRailPlayback, &mut Transform, Option<&SpectatorCamera>)>,
+    mut finished: EventWriter<CameraRailFinished>,
+) {
+    let skipped = inputs.just_pressed(Action::Jump) || inputs.just_pressed(Action::Pause);
+    let mut finished_rails = Vec::new();
+    for (entity, camera, mut playback, mut transform, spectator) in camera_query.iter_mut() {
+        // Spectators keep the player's inputs suspended
+        let resume = |commands: &mut Commands| {
+            commands.entity(entity).remove::<CameraRailPlayback>();
+            if spectator.is_none() {
+                commands.entity(camera.player).remove::<InputSuspended>();
+            }
+        };
+        let rail = if let Ok(rail) = rail_query.get(playback.rail) { rail } else {
+            resume(&mut commands);
+            continue;
+        };
+        playback.elapsed += time.delta_seconds();
+        let t = (playback.elapsed / rail.duration.max(f32::EPSILON)).min(1.);
+        // Ease in and out of the fly-through
+        let eased = t * t * (3. - 2. * t);
+        transform.translation = rail.position(eased);
+        transform.look_at(rail.look_at, Vec3::Y);
+
+        if skipped || t >= 1. {
+            resume(&mut commands);
+            if !finished_rails.contains(&playback.rail) {
+                finished_rails.push(playback.rail);
+                finished.send(CameraRailFinished { rail: playback.rail, skipped });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rail(points: Vec<Vec3>) -> CameraRail {
+        CameraRail { points, look_at: Vec3::ZERO, duration: 1., autoplay: false }
+    }
+
+    #[test]
+    fn position_starts_and_ends_on_the_end_points() {
+        let rail = rail(vec![Vec3::ZERO, Vec3::new(5., 2., 0.), Vec3::new(5., 4., 5.), Vec3::new(0., 6., 5.)]);
+        assert!(rail.position(0.).abs_diff_eq(Vec3::ZERO, 1e-5));
+        assert!(rail.position(1.).abs_diff_eq(Vec3::new(0., 6., 5.), 1e-5));
+        // Clamped outside of 0 to 1
+        assert!(rail.position(-1.).abs_diff_eq(Vec3::ZERO, 1e-5));
+        assert!(rail.position(2.).abs_diff_eq(Vec3::new(0., 6., 5.), 1e-5));
+        // Goes through the points in between
+        assert!(rail.position(1. / 3.).abs_diff_eq(Vec3::new(5., 2., 0.), 1e-5));
+    }
+
+    #[test]
+    fn position_of_short_rails() {
+        assert_eq!(rail(Vec::new()).position(0.5), Vec3::ZERO);
+        assert_eq!(rail(vec![Vec3::ONE]).position(0.5), Vec3::ONE);
+        assert!(rail(vec![Vec3::ZERO, Vec3::X]).position(1.).abs_diff_eq(Vec3::X, 1e-5));
+    }
+}
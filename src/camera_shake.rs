@@ -0,0 +1,112 @@
+use bevy::prelude::*;
+
+use crate::{camera::{camera_movement, MainCamera}, camera_rail::CameraRailPlayback, character_controller::CharacterControllerLabel, settings::Settings, spectator::SpectatorCamera};
+
+pub struct CameraShakePlugin;
+
+// Trauma from common gameplay events, from 0 to 1
+pub const DASH_SHAKE: f32 = 0.15;
+// Per unit of impulse, the debug knockback adds about 0.3
+pub const KNOCKBACK_SHAKE: f32 = 0.03;
+// Landings from lower than this don't shake, higher ones add trauma per extra meter
+pub const LANDING_SHAKE_HEIGHT: f32 = 4.;
+pub const LANDING_SHAKE: f32 = 0.05;
+
+// Trauma lost per second
+const TRAUMA_DECAY: f32 = 1.2;
+// Offsets at full trauma, before the accessibility scale
+const MAX_OFFSET: f32 = 0.4;
+const MAX_ANGLE: f32 = 0.08;
+// Noise samples per second, higher shakes faster
+const NOISE_FREQUENCY: f32 = 18.;
+
+/// Adds `intensity` trauma, from 0 to 1, to the camera of `player`, or to every camera when `None`.
+/// The shake grows with the square of the trauma, so small hits stay subtle.
+pub struct CameraShake {
+    pub player: Option<Entity>,
+    pub intensity: f32,
+}
+
+/// Shake state of a `MainCamera`, added by `camera_shake_system`.
+#[derive(Component, Default)]
+pub struct CameraTrauma {
+    pub trauma: f32,
+    // Moves through the noise while shaking
+    time: f32,
+}
+
+impl Plugin for CameraShakePlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .add_event::<CameraShake>()
+        // Shakes are sent before the players move
+        .add_system(add_camera_trauma.after(CharacterControllerLabel::Move))
+        .add_system(camera_shake_system.after(add_camera_trauma).after(camera_movement));
+    }
+}
+
+fn add_camera_trauma(
+    mut commands: Commands,
+    mut shakes: EventReader<CameraShake>,
+    mut camera_query: Query<(Entity, &MainCamera, Option<&mut CameraTrauma>)>,
+) {
+    for ev in shakes.iter() {
+        for (entity, camera, trauma) in camera_query.iter_mut() {
+            if ev.player.is_some_and(|player| player != camera.player) {
+                continue;
+            }
+            match trauma {
+                Some(mut trauma) => trauma.trauma = (trauma.trauma + ev.intensity).clamp(0., 1.),
+                None => {
+                    commands.entity(entity).insert(CameraTrauma { trauma: ev.intensity.clamp(0., 1.), ..default() });
+                }
+            }
+        }
+    }
+}
+
+/// Offsets the transform `camera_movement` just set, it is recomputed from scratch every frame.
+/// Cameras on a rail or spectating aren't offset, but their trauma still wears off.
+#[allow(clippy::type_complexity)]
+fn camera_shake_system(
+    time: Res<Time>,
+    settings: Res<Settings>,
+    mut camera_query: Query<(&mut CameraTrauma, &mut Transform, Option<&CameraRailPlayback>, Option<&SpectatorCamera>), With<MainCamera>>,
+) {
+    let dt = time.delta_seconds();
+    for (mut trauma, mut transform, rail, spectator) in camera_query.iter_mut() {
+        if trauma.trauma <= 0. {
+            continue;
+        }
+        trauma.time += dt * NOISE_FREQUENCY;
+        let shake = trauma.trauma * trauma.trauma * settings.accessibility.camera_shake.max(0.);
+        trauma.trauma = (trauma.trauma - TRAUMA_DECAY * dt).max(0.);
+        if shake <= 0. || rail.is_some() || spectator.is_some() {
+            continue;
+        }
+
+        let t = trauma.time;
+        let offset = Vec3::new(noise(0, t), noise(1, t), noise(2, t)) * MAX_OFFSET * shake;
+        let (yaw, pitch, roll) = (noise(3, t) * MAX_ANGLE * shake, noise(4, t) * MAX_ANGLE * shake, noise(5, t) * MAX_ANGLE * shake);
+        let offset = transform.rotation * offset;
+        transform.translation += offset;
+        transform.rotation *= Quat::from_euler(EulerRot::YXZ, yaw, pitch, roll);
+    }
+}
+
+/// Smooth value noise from -1 to 1, a different curve for each `seed`.
+fn noise(seed: u32, t: f32) -> f32 {
+    let lattice = |i: i32| {
+        let mut hash = (i as u32).wrapping_mul(0x27d4_eb2d) ^ seed.wrapping_mul(0x9e37_79b9);
+        hash ^= hash >> 15;
+        hash = hash.wrapping_mul(0x85eb_ca6b);
+        hash ^= hash >> 13;
+        hash as f32 / u32::MAX as f32 * 2. - 1.
+    };
+    let i = t.floor();
+    let local = t - i;
+    let smooth = local * local * (3. - 2. * local);
+    let a = lattice(i as i32);
+    let b = lattice(i as i32 + 1);
+    a + (b - a) * smooth
+}
@@ -1,12 +1,171 @@
+use std::sync::Arc;
+
 use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
 
 pub struct CharacterControllerPlugin;
 
+/// Kinematic movement for any entity (player, bot or enemy).
+/// Gameplay systems write the desired `velocity` and the plugin turns it into
+/// a rapier `KinematicCharacterController` translation that handles slopes,
+/// step-up, snap-to-ground and sliding along walls.
+#[derive(Component, Clone)]
+pub struct CharacterController {
+    pub velocity: Vec3,
+    pub gravity_scale: f32,
+    // Turned off while something else (e.g. a ragdoll) moves the body
+    pub enabled: bool,
+    pub is_grounded: bool,
+    // Shape used for the movement sweeps, relative to the entity
+    pub shape: Collider,
+    pub shape_offset: Vec3,
+    pub max_slope_climb_angle: f32,
+    pub min_slope_slide_angle: f32,
+    pub step_height: f32,
+    pub snap_to_ground: f32,
+    pub groups: CollisionGroups,
+}
+
+impl CharacterController {
+    pub fn new(shape: Collider, shape_offset: Vec3) -> Self {
+        Self {
+            velocity: Vec3::ZERO,
+            gravity_scale: 1.,
+            enabled: true,
+            is_grounded: false,
+            shape,
+            shape_offset,
+            max_slope_climb_angle: 45_f32.to_radians(),
+            min_slope_slide_angle: 30_f32.to_radians(),
+            step_height: 0.3,
+            snap_to_ground: 0.3,
+            groups: CollisionGroups::new(Group::GROUP_10, Group::GROUP_1),
+        }
+    }
+}
+
+/// The settings last copied into the rapier controller, they are only copied again when they change.
+/// `CharacterController` itself changes every frame with its velocity.
 #[derive(Component)]
-pub struct CharacterController;
+struct AppliedSettings {
+    shape: Collider,
+    shape_offset: Vec3,
+    max_slope_climb_angle: f32,
+    min_slope_slide_angle: f32,
+    step_height: f32,
+    snap_to_ground: f32,
+    groups: CollisionGroups,
+}
+
+impl AppliedSettings {
+    fn new(controller: &CharacterController) -> Self {
+        Self {
+            shape: controller.shape.clone(),
+            shape_offset: controller.shape_offset,
+            max_slope_climb_angle: controller.max_slope_climb_angle,
+            min_slope_slide_angle: controller.min_slope_slide_angle,
+            step_height: controller.step_height,
+            snap_to_ground: controller.snap_to_ground,
+            groups: controller.groups,
+        }
+    }
+
+    fn matches(&self, controller: &CharacterController) -> bool {
+        // Shapes are shared, a new or rescaled one is a different allocation
+        std::ptr::addr_eq(Arc::as_ptr(&self.shape.raw.0), Arc::as_ptr(&controller.shape.raw.0))
+            && self.shape_offset == controller.shape_offset
+            && self.max_slope_climb_angle == controller.max_slope_climb_angle
+            && self.min_slope_slide_angle == controller.min_slope_slide_angle
+            && self.step_height == controller.step_height
+            && self.snap_to_ground == controller.snap_to_ground
+            && self.groups == controller.groups
+    }
+}
+
+/// Labels to order gameplay systems around the controller.
+/// Anything writing `CharacterController.velocity` should run
+/// after `ReadOutput` and before `Move`.
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CharacterControllerLabel {
+    ReadOutput,
+    Move,
+}
 
 impl Plugin for CharacterControllerPlugin {
-    fn build(&self, _app: &mut App) {
+    fn build(&self, app: &mut App) {
+        app
+        .add_system(setup_character_controller.before(CharacterControllerLabel::ReadOutput))
+        .add_system(read_controller_output.label(CharacterControllerLabel::ReadOutput))
+        .add_system(move_character_controller.label(CharacterControllerLabel::Move).after(CharacterControllerLabel::ReadOutput));
+    }
+}
+
+fn setup_character_controller(
+    mut commands: Commands,
+    query: Query<(Entity, &CharacterController), Added<CharacterController>>,
+) {
+    for (entity, controller) in query.iter() {
+        let mut kinematic_controller = KinematicCharacterController {
+            offset: CharacterLength::Absolute(0.02),
+            slide: true,
+            ..default()
+        };
+        apply_controller_settings(controller, &mut kinematic_controller);
+        commands.entity(entity)
+            .insert(RigidBody::KinematicPositionBased)
+            .insert(kinematic_controller)
+            .insert(AppliedSettings::new(controller));
+    }
+}
+
+fn apply_controller_settings(controller: &CharacterController, kinematic_controller: &mut KinematicCharacterController) {
+    kinematic_controller.custom_shape = Some((controller.shape.clone(), controller.shape_offset, Quat::IDENTITY));
+    kinematic_controller.autostep = Some(CharacterAutostep {
+        max_height: CharacterLength::Absolute(controller.step_height),
+        min_width: CharacterLength::Absolute(0.2),
+        include_dynamic_bodies: false,
+    });
+    kinematic_controller.max_slope_climb_angle = controller.max_slope_climb_angle;
+    kinematic_controller.min_slope_slide_angle = controller.min_slope_slide_angle;
+    kinematic_controller.snap_to_ground = Some(CharacterLength::Absolute(controller.snap_to_ground));
+    kinematic_controller.filter_groups = Some(controller.groups.into());
+}
+
+fn read_controller_output(
+    mut query: Query<(&mut CharacterController, &KinematicCharacterControllerOutput)>,
+) {
+    for (mut controller, output) in query.iter_mut() {
+        if !controller.enabled {
+            continue;
+        }
+        controller.is_grounded = output.grounded;
+        if output.grounded && controller.velocity.y < 0. {
+            controller.velocity.y = 0.;
+        }
+        // Hit a ceiling while going up
+        if controller.velocity.y > 0. && output.effective_translation.y < output.desired_translation.y - 0.001 {
+            controller.velocity.y = 0.;
+        }
+    }
+}
 
+fn move_character_controller(
+    time: Res<Time>,
+    rapier_config: Res<RapierConfiguration>,
+    mut query: Query<(&mut CharacterController, &mut KinematicCharacterController, &mut AppliedSettings)>,
+) {
+    let delta = time.delta_seconds();
+    for (mut controller, mut kinematic_controller, mut applied) in query.iter_mut() {
+        if !applied.matches(&controller) {
+            apply_controller_settings(&controller, &mut kinematic_controller);
+            *applied = AppliedSettings::new(&controller);
+        }
+        if !controller.enabled {
+            kinematic_controller.translation = None;
+            continue;
+        }
+        let gravity = rapier_config.gravity * controller.gravity_scale;
+        controller.velocity += gravity * delta;
+        kinematic_controller.translation = Some(controller.velocity * delta);
     }
 }
@@ -1,6 +1,6 @@
 use bevy::{prelude::*, diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin}};
 
-use crate::player::Player;
+use crate::{camera::CameraConfig, camera_rail::{CameraRailFinished, CameraRailStarted}, gamepad::Inputs, grab::{GrabReleased, GrabStarted}, input_devices::{InputDevice, LastUsedDevice}, input_map::{Action, Rebinding}, knockback::Knockback, player::Player, player_config::{PlayerConfigAsset, PlayerConfigPreset}, player_slots::{PlayerJoined, PlayerLeft, PlayerSlot}, spectator::ToggleSpectator};
 
 pub struct DebugModePlugin;
 
@@ -9,7 +9,15 @@ impl Plugin for DebugModePlugin {
         app
         .add_startup_system(infotext_system)
         .add_system(update_fps_text)
-        .add_system(update_player_text);
+        .add_system(update_player_text)
+        .add_system(cycle_player_config_preset)
+        .add_system(log_grab_events)
+        .add_system(log_player_slot_events)
+        .add_system(log_camera_rail_events)
+        .add_system(debug_knockback)
+        .add_system(debug_rebind_jump)
+        .add_system(toggle_camera_lock_on)
+        .add_system(toggle_spectator_camera);
     }
 }
 
@@ -95,6 +103,38 @@ fn infotext_system(mut commands: Commands, asset_server: Res<AssetServer>) {
                     color: Color::WHITE,
                 },
             ),
+            TextSection::new(
+                "\nCoyote time: 0.00",
+                TextStyle {
+                    font: font.clone(),
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::new(
+                "\nJump buffer: 0.00",
+                TextStyle {
+                    font: font.clone(),
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::new(
+                "\nStun: 0.00",
+                TextStyle {
+                    font: font.clone(),
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::new(
+                "\nDevice: keyboard",
+                TextStyle {
+                    font: font.clone(),
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                },
+            ),
         ])
         .with_style(Style {
             position_type: PositionType::Absolute,
@@ -134,6 +174,7 @@ fn infotext_system(mut commands: Commands, asset_server: Res<AssetServer>) {
 fn update_fps_text(
     time: Res<Time>,
     diagnostics: Res<Diagnostics>,
+    inputs: Res<Inputs>,
     mut query: Query<&mut Text, With<FPSText>>,
 ) {
     for mut text in &mut query {
@@ -153,26 +194,141 @@ fn update_fps_text(
         }
 
         text.sections[0].value = format!(
-            "{:.1} fps, {:.3} ms/frame",
-            fps, frame_time,
+            "{:.1} fps, {:.3} ms/frame, input frame {}",
+            fps, frame_time, inputs.frame,
         );
     }
 }
 
 fn update_player_text(
     mut text_query: Query<&mut Text, With<PlayerInfoText>>,
-    player_query: Query<&Player, With<Player>>,
+    player_query: Query<(&Player, &PlayerSlot)>,
+    last_used: Res<LastUsedDevice>,
 ) {
     for mut text in &mut text_query {
-        if let Ok(player) = player_query.get_single() {
+        // Only the first player's state is shown
+        if let Some((player, _)) = player_query.iter().find(|(_, slot)| slot.index == 0) {
             text.sections[2].value = player.is_jumping.to_string();
             text.sections[2].style.color = if player.is_jumping { Color::GREEN } else { Color::RED };
             text.sections[4].value = player.is_grounded.to_string();
             text.sections[4].style.color = if player.is_grounded { Color::GREEN } else { Color::RED };
             text.sections[6].value = player.is_dashing.to_string();
             text.sections[6].style.color = if player.is_dashing { Color::GREEN } else { Color::RED };
-            text.sections[7].value = format!("\nDashes: {}", player.dashes);
+            text.sections[7].value = format!("\nDashes: {}", player.dash_charges);
             text.sections[8].value = format!("\nJumps without ground: {}", player.jumps_without_ground);
+            text.sections[9].value = format!("\nCoyote time: {:.2}", player.coyote_timer);
+            text.sections[10].value = format!("\nJump buffer: {:.2}", player.jump_buffer_timer);
+            text.sections[11].value = format!("\nStun: {:.2}", player.stun_timer);
+            text.sections[11].style.color = if player.is_stunned() { Color::ORANGE } else { Color::WHITE };
+            text.sections[12].value = match last_used.0 {
+                InputDevice::KeyboardMouse => "\nDevice: keyboard".to_string(),
+                InputDevice::Gamepad(gamepad) => format!("\nDevice: gamepad {}", gamepad.id),
+            };
         }
     }
-}
\ No newline at end of file
+}
+
+fn cycle_player_config_preset(
+    kb: Res<Input<KeyCode>>,
+    assets: Res<Assets<PlayerConfigAsset>>,
+    mut preset: ResMut<PlayerConfigPreset>,
+) {
+    if !kb.just_pressed(KeyCode::F1) {
+        return;
+    }
+    if let Some(asset) = assets.get(&preset.handle) {
+        let names = asset.preset_names();
+        let current = preset.preset.as_ref().unwrap_or(&asset.default_preset);
+        let next = names.iter().position(|name| name == current).map_or(0, |i| (i + 1) % names.len());
+        preset.preset = names.get(next).cloned();
+    }
+}
+
+fn log_grab_events(
+    mut started: EventReader<GrabStarted>,
+    mut released: EventReader<GrabReleased>,
+) {
+    for ev in started.iter() {
+        info!("{:?} grabbed {:?}", ev.grabber, ev.target);
+    }
+    for ev in released.iter() {
+        info!("{:?} released {:?}: {:?}", ev.grabber, ev.target, ev.reason);
+    }
+}
+
+fn log_player_slot_events(
+    mut joined: EventReader<PlayerJoined>,
+    mut left: EventReader<PlayerLeft>,
+) {
+    for ev in joined.iter() {
+        info!("Player {} joined with {:?} as {:?}", ev.slot.index + 1, ev.slot.device, ev.player);
+    }
+    for ev in left.iter() {
+        info!("Player {} left, {:?} despawned", ev.slot.index + 1, ev.player);
+    }
+}
+
+fn log_camera_rail_events(
+    mut started: EventReader<CameraRailStarted>,
+    mut finished: EventReader<CameraRailFinished>,
+) {
+    for ev in started.iter() {
+        info!("Camera rail {:?} started", ev.rail);
+    }
+    for ev in finished.iter() {
+        info!("Camera rail {:?} finished{}", ev.rail, if ev.skipped { ", skipped" } else { "" });
+    }
+}
+
+/// Knocks the player back from where it is facing, to test the ragdoll.
+fn debug_knockback(
+    kb: Res<Input<KeyCode>>,
+    player_query: Query<(Entity, &Transform), With<Player>>,
+    mut knockback: EventWriter<Knockback>,
+) {
+    if !kb.just_pressed(KeyCode::K) {
+        return;
+    }
+    for (entity, transform) in player_query.iter() {
+        knockback.send(Knockback {
+            target: entity,
+            impulse: transform.forward() * 8. + Vec3::Y * 5.,
+            stun_time: None,
+        });
+    }
+}
+
+/// Binds the next key or button pressed to jump.
+fn debug_rebind_jump(
+    kb: Res<Input<KeyCode>>,
+    mut rebinding: ResMut<Rebinding>,
+) {
+    if kb.just_pressed(KeyCode::F2) {
+        info!("Press a key or button for {:?}", Action::Jump);
+        rebinding.action = Some(Action::Jump);
+    }
+}
+
+fn toggle_camera_lock_on(
+    kb: Res<Input<KeyCode>>,
+    mut config: ResMut<CameraConfig>,
+) {
+    if kb.just_pressed(KeyCode::F4) {
+        config.lock_on = !config.lock_on;
+        info!("Camera lock-on: {}", config.lock_on);
+    }
+}
+
+/// Free flying camera for the first player, to look around the level.
+fn toggle_spectator_camera(
+    kb: Res<Input<KeyCode>>,
+    player_query: Query<(Entity, &PlayerSlot)>,
+    mut toggles: EventWriter<ToggleSpectator>,
+) {
+    if !kb.just_pressed(KeyCode::F3) {
+        return;
+    }
+    if let Some((player, _)) = player_query.iter().find(|(_, slot)| slot.index == 0) {
+        toggles.send(ToggleSpectator { player });
+    }
+}
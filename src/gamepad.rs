@@ -1,19 +1,30 @@
-use bevy::{prelude::*};
+use std::collections::BTreeMap;
 
-/// Simple resource to store the ID of the connected gamepad.
-/// We need to know which gamepad to use for player input.
-#[derive(Resource)]
-struct MyGamepad(Gamepad);
+use bevy::{prelude::*, input::InputSystem};
 
-#[derive(Clone, Resource)]
+use crate::{input_devices::{DeviceInputs, InputDevice, InputLabel}, input_map::{strongest, Action, Binding, InputMap}, settings::Settings};
+
+/// Held state of a button action, with the edges of this frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ButtonState {
+    pub pressed: bool,
+    pub just_pressed: bool,
+    pub just_released: bool,
+}
+
+/// Inputs of a single player as a component, or of every device merged as a resource.
+#[derive(Clone, Resource, Component)]
 pub struct Inputs {
     // Player movement
     pub player_movement: Vec2,
-    // Camera movement
+    // Camera movement, positive y tilts the view down like moving the mouse down
     pub camera_movement: Vec2,
-    pub camera_angle: f32,
-    pub jump_button: bool,
-    pub dash_button: bool,
+    // Positive zooms in
+    pub camera_zoom: f32,
+    // State of every button action, see `Action::BUTTONS`
+    buttons: BTreeMap<Action, ButtonState>,
+    // Frames since the game started, counted by the input aggregation
+    pub frame: u64,
 }
 
 impl Inputs {
@@ -21,25 +32,78 @@ impl Inputs {
         Self {
             camera_movement: Vec2::default(),
             player_movement: Vec2::default(),
-            camera_angle: 0.,
-            jump_button: false,
-            dash_button: false
+            camera_zoom: 0.,
+            buttons: BTreeMap::new(),
+            frame: 0
         }
     }
+
+    pub fn button(&self, action: Action) -> ButtonState {
+        self.buttons.get(&action).copied().unwrap_or_default()
+    }
+
+    pub fn pressed(&self, action: Action) -> bool {
+        self.button(action).pressed
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.button(action).just_pressed
+    }
+
+    pub fn just_released(&self, action: Action) -> bool {
+        self.button(action).just_released
+    }
+
+    /// Devices only report whether a button is held, the edges are found by `update_edges`.
+    pub fn set_pressed(&mut self, action: Action, pressed: bool) {
+        self.buttons.entry(action).or_default().pressed = pressed;
+    }
+
+    /// Sets the just pressed / just released state by comparing with last frame's inputs.
+    pub fn update_edges(&mut self, previous: &Inputs) {
+        for action in Action::BUTTONS {
+            let was_pressed = previous.pressed(action);
+            let state = self.buttons.entry(action).or_default();
+            state.just_pressed = state.pressed && !was_pressed;
+            state.just_released = !state.pressed && was_pressed;
+        }
+    }
+
+    /// Combines another device's inputs into these: the strongest axis wins, buttons are OR'd.
+    pub fn merge(&mut self, other: &Inputs) {
+        let strongest_vec = |a: Vec2, b: Vec2| Vec2::new(
+            strongest([a.x, b.x].into_iter()),
+            strongest([a.y, b.y].into_iter())
+        );
+        self.player_movement = strongest_vec(self.player_movement, other.player_movement);
+        self.camera_movement = strongest_vec(self.camera_movement, other.camera_movement);
+        self.camera_zoom = strongest([self.camera_zoom, other.camera_zoom].into_iter());
+        for (action, state) in other.buttons.iter() {
+            self.buttons.entry(*action).or_default().pressed |= state.pressed;
+        }
+    }
+
+    /// Whether anything is being pressed or moved, ignoring small stick drift.
+    pub fn is_active(&self) -> bool {
+        self.player_movement.length() > 0.1
+            || self.camera_movement.length() > 0.1
+            || self.camera_zoom != 0.
+            || self.buttons.values().any(|state| state.pressed)
+    }
 }
+
 pub struct GamepadControllerPlugin;
 
 impl Plugin for GamepadControllerPlugin {
     fn build(&self, app: &mut App) {
         app
-        .add_system(gamepad_connections)
-        .add_system(gamepad_movement);
+        .add_system_to_stage(CoreStage::PreUpdate, gamepad_connections.label(InputLabel::Read).after(InputSystem))
+        .add_system_to_stage(CoreStage::PreUpdate, gamepad_movement.label(InputLabel::Read).after(gamepad_connections));
     }
 }
 
 fn gamepad_connections(
-    mut commands: Commands,
-    my_gamepad: Option<Res<MyGamepad>>,
+    mut device_inputs: ResMut<DeviceInputs>,
     mut gamepad_evr: EventReader<GamepadEvent>,
 ) {
     for ev in gamepad_evr.iter() {
@@ -47,26 +111,11 @@ fn gamepad_connections(
         let id = ev.gamepad;
         match &ev.event_type {
             GamepadEventType::Connected(info) => {
-                println!("New gamepad connected with ID: {:?}, name: {}", id, info.name);
-
-                // if we don't have any gamepad yet, use this one
-                dbg!(my_gamepad.is_none());
-                if my_gamepad.is_none() {
-                    println!("Adding gamepad resource");
-                    commands.insert_resource(MyGamepad(id));
-                }
+                info!("New gamepad connected with ID: {:?}, name: {}", id, info.name);
             }
             GamepadEventType::Disconnected => {
-                println!("Lost gamepad connection with ID: {:?}", id);
-
-                // if it's the one we previously associated with the player,
-                // disassociate it:
-                if let Some(MyGamepad(old_id)) = my_gamepad.as_deref() {
-                    if *old_id == id {
-                        println!("Removing gamepad resource");
-                        commands.remove_resource::<MyGamepad>();
-                    }
-                }
+                info!("Lost gamepad connection with ID: {:?}", id);
+                device_inputs.0.remove(&InputDevice::Gamepad(id));
             }
             // other events are irrelevant
             _ => {}
@@ -75,53 +124,101 @@ fn gamepad_connections(
 }
 
 fn gamepad_movement(
-    mut commands: Commands,
     axes: Res<Axis<GamepadAxis>>,
-    my_gamepad: Option<Res<MyGamepad>>,
+    gamepads: Res<Gamepads>,
     buttons: Res<Input<GamepadButton>>,
-    inputs: Res<Inputs>
+    input_map: Res<InputMap>,
+    settings: Res<Settings>,
+    mut device_inputs: ResMut<DeviceInputs>
 ) {
-    let gamepad = if let Some(gp) = my_gamepad {
-        gp.0
-    } else {
-        return;
-    };
+    for gamepad in gamepads.iter() {
+        device_inputs.0.insert(InputDevice::Gamepad(gamepad), gamepad_inputs(gamepad, &axes, &buttons, &input_map, &settings));
+    }
+}
 
-    // The joysticks are represented using a separate axis for X and Y
-    let axis_lx = GamepadAxis {
-        gamepad, axis_type: GamepadAxisType::LeftStickX
-    };
-    let axis_ly = GamepadAxis {
-        gamepad, axis_type: GamepadAxisType::LeftStickY
-    };
+fn gamepad_inputs(
+    gamepad: Gamepad,
+    axes: &Axis<GamepadAxis>,
+    buttons: &Input<GamepadButton>,
+    input_map: &InputMap,
+    settings: &Settings
+) -> Inputs {
+    let value = |action: Action| strongest(
+        input_map.bindings(action).iter().map(|binding| binding_value(binding, gamepad, axes, buttons))
+    );
+    let pressed = |action: Action| value(action).abs() >= 0.5;
 
-    let axis_rx = GamepadAxis {
-        gamepad, axis_type: GamepadAxisType::RightStickX
-    };
-    let axis_ry = GamepadAxis {
-        gamepad, axis_type: GamepadAxisType::RightStickY
-    };
+    let mut new_inputs = Inputs::default();
+    for action in Action::BUTTONS {
+        new_inputs.set_pressed(action, pressed(action));
+    }
 
-    // In a real game, the buttons would be configurable, but here we hardcode them
-    let jump_button = GamepadButton {
-        gamepad, button_type: GamepadButtonType::South
-    };
-    let dash_button = GamepadButton {
-        gamepad, button_type: GamepadButtonType::West
+    let input_settings = &settings.input;
+    new_inputs.player_movement = input_settings.gamepad_move_stick.apply(Vec2::new(value(Action::MoveX), value(Action::MoveY)));
+    let mut camera_movement = input_settings.gamepad_camera_stick.apply(Vec2::new(value(Action::CameraX), value(Action::CameraY)));
+    // Stick up looks up, unless inverted
+    if !input_settings.gamepad_invert_y {
+        camera_movement.y = -camera_movement.y;
+    }
+    new_inputs.camera_movement = camera_movement * input_settings.gamepad_camera_sensitivity;
+    new_inputs.camera_zoom = value(Action::Zoom);
+    new_inputs
+}
+
+fn binding_value(
+    binding: &Binding,
+    gamepad: Gamepad,
+    axes: &Axis<GamepadAxis>,
+    buttons: &Input<GamepadButton>
+) -> f32 {
+    let button_value = |button_type: GamepadButtonType| {
+        if buttons.pressed(GamepadButton { gamepad, button_type }) { 1. } else { 0. }
     };
+    match binding {
+        Binding::GamepadAxis(axis_type) => axes.get(GamepadAxis { gamepad, axis_type: *axis_type }).unwrap_or(0.),
+        Binding::GamepadButton(button_type) => button_value(*button_type),
+        Binding::GamepadButtonAxis { negative, positive } => button_value(*positive) - button_value(*negative),
+        _ => 0.,
+    }
+}
 
-    let mut new_inputs = inputs.clone();
-    new_inputs.jump_button = buttons.just_pressed(jump_button);
-    new_inputs.dash_button = buttons.just_pressed(dash_button);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if let (Some(x), Some(z)) = (axes.get(axis_lx), axes.get(axis_ly)) {
-        new_inputs.player_movement = Vec2::new(x, z);
+    fn with_jump(pressed: bool) -> Inputs {
+        let mut inputs = Inputs::default();
+        inputs.set_pressed(Action::Jump, pressed);
+        inputs
     }
 
-    if let (Some(x), Some(z)) = (axes.get(axis_rx), axes.get(axis_ry)) {
-        new_inputs.camera_movement = Vec2::new(x, z);
+    #[test]
+    fn update_edges_finds_presses_and_releases() {
+        let mut pressed = with_jump(true);
+        pressed.update_edges(&with_jump(false));
+        assert!(pressed.pressed(Action::Jump));
+        assert!(pressed.just_pressed(Action::Jump));
+        assert!(!pressed.just_released(Action::Jump));
+
+        let mut held = with_jump(true);
+        held.update_edges(&pressed);
+        assert!(!held.just_pressed(Action::Jump));
+
+        let mut released = with_jump(false);
+        released.update_edges(&held);
+        assert!(released.just_released(Action::Jump));
+        assert!(!released.just_pressed(Action::Jump));
     }
 
-    commands.remove_resource::<Inputs>();
-    commands.insert_resource(new_inputs);
-}
\ No newline at end of file
+    #[test]
+    fn merge_keeps_the_strongest_axis_and_ors_buttons() {
+        let mut keyboard = with_jump(false);
+        keyboard.player_movement = Vec2::new(1., 0.);
+        let mut gamepad = with_jump(true);
+        gamepad.player_movement = Vec2::new(-0.5, -0.8);
+
+        keyboard.merge(&gamepad);
+        assert_eq!(keyboard.player_movement, Vec2::new(1., -0.8));
+        assert!(keyboard.pressed(Action::Jump));
+    }
+}
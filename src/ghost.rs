@@ -0,0 +1,222 @@
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{character_controller::CharacterController, gamepad::Inputs, input_map::Action, level::{LevelLoaded, SpawnPoints}, player::{player_model, Player, PlayerAnimation}, player_slots::PlayerSlot, replay::CurrentLevel, ron_file};
+
+pub struct GhostPlugin;
+
+// The best run of each level is saved as `<level>.ron` in here
+const GHOST_DIR: &str = "saves/ghosts";
+const GHOST_ALPHA: f32 = 0.35;
+
+/// Reaching this box ends the run, `half_extents` around the entity's translation.
+#[derive(Component)]
+pub struct FinishLine {
+    pub half_extents: Vec3,
+}
+
+/// The run of the first player, and the best one of this level.
+#[derive(Resource, Default)]
+pub struct TimeTrial {
+    pub elapsed: f32,
+    // Starts with the first player's first move, the clock doesn't run before
+    run: Option<GhostRun>,
+    pub best: Option<GhostRun>,
+}
+
+/// The first player on every frame of a run, shown as the ghost without simulating it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GhostRun {
+    pub level: String,
+    pub poses: Vec<RecordedPose>,
+}
+
+/// Where a player was in a frame, and what it was doing.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct RecordedPose {
+    // Seconds since the start of the run
+    pub time: f32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub animation: PlayerAnimation,
+}
+
+impl GhostRun {
+    /// Seconds from the first to the last pose.
+    pub fn duration(&self) -> f32 {
+        self.poses.last().map_or(0., |pose| pose.time)
+    }
+}
+
+/// A translucent copy of the player model following `TimeTrial::best`.
+#[derive(Component)]
+pub struct Ghost {
+    // Pose shown last, playback only moves forward until the run restarts
+    frame: usize,
+}
+
+fn ghost_path(level: &str) -> PathBuf {
+    Path::new(GHOST_DIR).join(format!("{}.ron", level))
+}
+
+impl Plugin for GhostPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .init_resource::<TimeTrial>()
+        .add_startup_system(load_best_ghost)
+        .add_system(ghost_playback_system)
+        .add_system(ghost_material_system)
+        .add_system_to_stage(CoreStage::PostUpdate, time_trial_system);
+    }
+}
+
+fn load_best_ghost(
+    mut commands: Commands,
+    ass: Res<AssetServer>,
+    level: Res<CurrentLevel>,
+    spawn_points: Res<SpawnPoints>,
+    mut trial: ResMut<TimeTrial>,
+) {
+    trial.best = ron_file::load(ghost_path(&level.0));
+    if let Some(best) = &trial.best {
+        info!("Best time on {}: {:.2}s", level.0, best.duration());
+        spawn_ghost(&mut commands, &ass, spawn_points.get(0));
+    }
+}
+
+fn spawn_ghost(commands: &mut Commands, ass: &AssetServer, position: Vec3) {
+    commands.spawn(player_model(ass, Transform::from_translation(position)))
+        .insert(PlayerAnimation::Idle)
+        .insert(Ghost { frame: 0 });
+}
+
+/// Times the first player, and saves the run as the ghost when it beats the best one.
+#[allow(clippy::too_many_arguments)]
+fn time_trial_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    ass: Res<AssetServer>,
+    level: Res<CurrentLevel>,
+    spawn_points: Res<SpawnPoints>,
+    mut loaded: EventReader<LevelLoaded>,
+    mut trial: ResMut<TimeTrial>,
+    mut player_query: Query<(&PlayerSlot, &Inputs, &mut Transform, &PlayerAnimation, &mut CharacterController), With<Player>>,
+    finish_query: Query<(&FinishLine, &GlobalTransform)>,
+    mut ghost_query: Query<&mut Ghost>,
+) {
+    let (_, inputs, mut transform, animation, mut controller) = if let Some(player) = player_query.iter_mut().find(|(slot, ..)| slot.index == 0) {
+        player
+    } else {
+        return;
+    };
+
+    // The players were just moved to the start of the new level
+    if loaded.iter().count() > 0 {
+        restart(&mut trial, &mut ghost_query);
+    }
+
+    if trial.run.is_none() {
+        let moved = inputs.player_movement != Vec2::ZERO
+            || [Action::Jump, Action::Dash, Action::Dive].iter().any(|action| inputs.pressed(*action));
+        if !moved {
+            return;
+        }
+        trial.run = Some(GhostRun { level: level.0.clone(), poses: Vec::new() });
+    }
+    trial.elapsed += time.delta_seconds();
+    let elapsed = trial.elapsed;
+    if let Some(run) = trial.run.as_mut() {
+        run.poses.push(RecordedPose {
+            time: elapsed,
+            translation: transform.translation,
+            rotation: transform.rotation,
+            animation: *animation,
+        });
+    }
+
+    let finished = finish_query.iter().any(|(finish, finish_transform)| {
+        let offset = (transform.translation - finish_transform.translation()).abs();
+        offset.cmple(finish.half_extents).all()
+    });
+    if !finished {
+        return;
+    }
+
+    if let Some(run) = trial.run.take() {
+        let is_best = trial.best.as_ref().is_none_or(|best| elapsed < best.duration());
+        if is_best {
+            info!("Finished in {:.2}s, new best!", elapsed);
+            ron_file::save(ghost_path(&level.0), &run, false);
+            if trial.best.is_none() {
+                spawn_ghost(&mut commands, &ass, spawn_points.get(0));
+            }
+            trial.best = Some(run);
+        } else {
+            info!("Finished in {:.2}s", elapsed);
+        }
+    }
+
+    // Start over, racing the best ghost
+    transform.translation = spawn_points.get(0);
+    controller.velocity = Vec3::ZERO;
+    restart(&mut trial, &mut ghost_query);
+}
+
+fn restart(trial: &mut TimeTrial, ghost_query: &mut Query<&mut Ghost>) {
+    trial.elapsed = 0.;
+    trial.run = None;
+    for mut ghost in ghost_query.iter_mut() {
+        ghost.frame = 0;
+    }
+}
+
+fn ghost_playback_system(
+    trial: Res<TimeTrial>,
+    mut ghost_query: Query<(&mut Ghost, &mut Transform, &mut PlayerAnimation)>,
+) {
+    let best = if let Some(best) = &trial.best { best } else { return };
+    for (mut ghost, mut transform, mut animation) in ghost_query.iter_mut() {
+        // Stays at the finish once its run is over
+        while ghost.frame + 1 < best.poses.len() && best.poses[ghost.frame + 1].time <= trial.elapsed {
+            ghost.frame += 1;
+        }
+        if let Some(pose) = best.poses.get(ghost.frame) {
+            transform.translation = pose.translation;
+            transform.rotation = pose.rotation;
+            if *animation != pose.animation {
+                *animation = pose.animation;
+            }
+        }
+    }
+}
+
+/// Swaps the materials of the ghost model for translucent copies once its scene is spawned.
+fn ghost_material_system(
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut material_query: Query<(Entity, &mut Handle<StandardMaterial>), Added<Handle<StandardMaterial>>>,
+    parent_query: Query<&Parent>,
+    ghost_query: Query<(), With<Ghost>>,
+) {
+    for (entity, mut handle) in material_query.iter_mut() {
+        let mut ancestor = entity;
+        let mut is_ghost = false;
+        while let Ok(parent) = parent_query.get(ancestor) {
+            ancestor = parent.get();
+            if ghost_query.contains(ancestor) {
+                is_ghost = true;
+                break;
+            }
+        }
+        if !is_ghost {
+            continue;
+        }
+        if let Some(material) = materials.get(&handle) {
+            let mut ghost_material = material.clone();
+            ghost_material.base_color.set_a(GHOST_ALPHA);
+            ghost_material.alpha_mode = AlphaMode::Blend;
+            *handle = materials.add(ghost_material);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{character_controller::{CharacterController, CharacterControllerLabel}, gamepad::Inputs, input_map::Action, level::Obstacle, player::{Player, PlayerLabel}, player_config::PlayerConfig};
+
+pub struct GrabPlugin;
+
+/// Sent when a player grabs another body.
+pub struct GrabStarted {
+    pub grabber: Entity,
+    pub target: Entity,
+}
+
+/// Sent when a grab ends, for any reason.
+pub struct GrabReleased {
+    pub grabber: Entity,
+    pub target: Entity,
+    pub reason: GrabReleaseReason,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrabReleaseReason {
+    LetGo,
+    OutOfStamina,
+    BrokeFree,
+}
+
+impl Plugin for GrabPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .add_event::<GrabStarted>()
+        .add_event::<GrabReleased>()
+        .add_system(grab_struggle_system.label(PlayerLabel::Grab).after(PlayerLabel::Knockback))
+        .add_system(player_grab_system.label(PlayerLabel::Grab).after(grab_struggle_system))
+        .add_system(grabbed_by_system.label(PlayerLabel::Grab).after(player_grab_system))
+        .add_system(grab_pull_system.after(PlayerLabel::Velocity).before(CharacterControllerLabel::Move))
+        .add_system(grab_release_system.after(player_grab_system));
+    }
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
+fn player_grab_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    rapier_context: Res<RapierContext>,
+    mut player_query: Query<(Entity, &Transform, &mut Player, &Inputs)>,
+    // Obstacles are moved by the level, not something to hold on to
+    target_query: Query<(&GlobalTransform, Option<&CharacterController>, Option<&Velocity>), (With<RigidBody>, Without<Obstacle>)>,
+    config: Res<PlayerConfig>,
+    mut started: EventWriter<GrabStarted>,
+    mut released: EventWriter<GrabReleased>,
+) {
+    for (entity, transform, mut player, inputs) in player_query.iter_mut() {
+        if let Some(target) = player.grabbing {
+            player.grab_stamina = (player.grab_stamina - time.delta_seconds() / config.grab_stamina_time).max(0.);
+            let hold_point = transform.translation + transform.back() * config.grab_hold_distance;
+            let reason = if !inputs.pressed(Action::Grab) || player.is_diving || player.is_stunned() {
+                Some(GrabReleaseReason::LetGo)
+            } else if player.grab_stamina <= 0. {
+                Some(GrabReleaseReason::OutOfStamina)
+            } else {
+                match target_query.get(target) {
+                    Ok((target_transform, ..)) if target_transform.translation().distance(hold_point) <= config.grab_break_distance => None,
+                    _ => Some(GrabReleaseReason::BrokeFree),
+                }
+            };
+            if let Some(reason) = reason {
+                player.grabbing = None;
+                released.send(GrabReleased { grabber: entity, target, reason });
+            }
+            continue;
+        }
+
+        player.grab_stamina = (player.grab_stamina + time.delta_seconds() / config.grab_stamina_recovery_time).min(1.);
+        if !inputs.pressed(Action::Grab) || player.is_diving || player.is_stunned() || player.grab_stamina < config.grab_min_stamina {
+            continue;
+        }
+
+        let grab_pos = transform.translation + transform.back() * config.grab_reach;
+        let filter = QueryFilter::exclude_fixed().exclude_rigid_body(entity);
+        let mut found = None;
+        rapier_context.intersections_with_shape(grab_pos, Quat::IDENTITY, &Collider::ball(config.grab_reach), filter, |collider| {
+            let body = rapier_context.collider_parent(collider).unwrap_or(collider);
+            if body != entity && target_query.contains(body) {
+                found = Some(body);
+                return false;
+            }
+            true
+        });
+
+        if let Some(target) = found {
+            // Dynamic bodies are pulled with a force, characters through their controller
+            if let Ok((_, None, velocity)) = target_query.get(target) {
+                commands.entity(target).insert(ExternalForce::default());
+                if velocity.is_none() {
+                    commands.entity(target).insert(Velocity::zero());
+                }
+            }
+            player.grabbing = Some(target);
+            started.send(GrabStarted { grabber: entity, target });
+        }
+    }
+}
+
+/// Grabbed players mash jump or dash to break free.
+fn grab_struggle_system(
+    time: Res<Time>,
+    mut player_query: Query<(Entity, &mut Player, &Inputs)>,
+    config: Res<PlayerConfig>,
+    mut released: EventWriter<GrabReleased>,
+) {
+    let grabs: Vec<(Entity, Entity)> = player_query.iter()
+        .filter_map(|(grabber, player, _)| player.grabbing.map(|target| (grabber, target)))
+        .collect();
+    for (entity, mut player, inputs) in player_query.iter_mut() {
+        if !grabs.iter().any(|(_, target)| *target == entity) {
+            player.grab_struggle = 0.;
+            continue;
+        }
+        let presses = [Action::Jump, Action::Dash].iter().filter(|action| inputs.just_pressed(**action)).count();
+        player.grab_struggle = (player.grab_struggle + presses as f32 * config.grab_struggle_per_press
+            - config.grab_struggle_decay * time.delta_seconds()).max(0.);
+    }
+
+    for (grabber, target) in grabs {
+        let broke_free = player_query.get(target).is_ok_and(|(_, player, _)| player.grab_struggle >= 1.);
+        if !broke_free {
+            continue;
+        }
+        if let Ok((_, mut player, _)) = player_query.get_mut(target) {
+            player.grab_struggle = 0.;
+        }
+        if let Ok((_, mut player, _)) = player_query.get_mut(grabber) {
+            player.grabbing = None;
+        }
+        released.send(GrabReleased { grabber, target, reason: GrabReleaseReason::BrokeFree });
+    }
+}
+
+/// Marks each player with who is holding them, for the movement to keep the pull.
+fn grabbed_by_system(mut player_query: Query<(Entity, &mut Player)>) {
+    let grabs: Vec<(Entity, Entity)> = player_query.iter()
+        .filter_map(|(grabber, player)| player.grabbing.map(|target| (grabber, target)))
+        .collect();
+    for (entity, mut player) in player_query.iter_mut() {
+        let grabbed_by = grabs.iter().find(|(_, target)| *target == entity).map(|(grabber, _)| *grabber);
+        if player.grabbed_by != grabbed_by {
+            player.grabbed_by = grabbed_by;
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn grab_pull_system(
+    time: Res<Time>,
+    player_query: Query<(&Transform, &Player)>,
+    mut target_query: Query<(&GlobalTransform, Option<&mut CharacterController>, Option<&mut ExternalForce>, Option<&Velocity>), Without<Player>>,
+    mut character_query: Query<(&GlobalTransform, &mut CharacterController), With<Player>>,
+    config: Res<PlayerConfig>,
+) {
+    for (transform, player) in player_query.iter() {
+        let target = if let Some(target) = player.grabbing { target } else { continue };
+        let hold_point = transform.translation + transform.back() * config.grab_hold_distance;
+
+        if let Ok((target_transform, mut controller)) = character_query.get_mut(target) {
+            let stretch = hold_point - target_transform.translation();
+            let pull = stretch * config.grab_stiffness - controller.velocity * config.grab_damping;
+            controller.velocity += pull * time.delta_seconds();
+        } else if let Ok((target_transform, controller, force, velocity)) = target_query.get_mut(target) {
+            let stretch = hold_point - target_transform.translation();
+            if let Some(mut controller) = controller {
+                let pull = stretch * config.grab_stiffness - controller.velocity * config.grab_damping;
+                controller.velocity += pull * time.delta_seconds();
+            } else if let Some(mut force) = force {
+                let damping = velocity.map_or(Vec3::ZERO, |v| v.linvel * config.grab_damping);
+                force.force = stretch * config.grab_stiffness - damping;
+            }
+        }
+    }
+}
+
+fn grab_release_system(
+    mut released: EventReader<GrabReleased>,
+    mut force_query: Query<&mut ExternalForce>,
+) {
+    for ev in released.iter() {
+        if let Ok(mut force) = force_query.get_mut(ev.target) {
+            force.force = Vec3::ZERO;
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::gamepad::Inputs;
+
+pub struct InputDevicesPlugin;
+
+/// Where an input came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum InputDevice {
+    KeyboardMouse,
+    Gamepad(Gamepad),
+}
+
+// Keyboard first, then gamepads by id, so merging doesn't depend on the map's order
+impl Ord for InputDevice {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let key = |device: &InputDevice| match device {
+            InputDevice::KeyboardMouse => (0, 0),
+            InputDevice::Gamepad(gamepad) => (1, gamepad.id),
+        };
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for InputDevice {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Device plugins read their input in `Read`, then `Aggregate` merges it into `Inputs`.
+#[derive(SystemLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InputLabel {
+    Read,
+    Aggregate,
+}
+
+/// What each device reported this frame, before merging.
+#[derive(Resource, Default)]
+pub struct DeviceInputs(pub HashMap<InputDevice, Inputs>);
+
+impl DeviceInputs {
+    /// Every device's inputs in a fixed order, the map's own order changes from run to run.
+    pub fn sorted(&self) -> Vec<(&InputDevice, &Inputs)> {
+        let mut devices: Vec<_> = self.0.iter().collect();
+        devices.sort_by_key(|(device, _)| **device);
+        devices
+    }
+}
+
+/// The device that was used last, to show the matching button prompts.
+#[derive(Resource)]
+pub struct LastUsedDevice(pub InputDevice);
+
+impl Plugin for InputDevicesPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .insert_resource(Inputs::default())
+        .init_resource::<DeviceInputs>()
+        .insert_resource(LastUsedDevice(InputDevice::KeyboardMouse))
+        .add_system_to_stage(CoreStage::PreUpdate, aggregate_inputs.label(InputLabel::Aggregate).after(InputLabel::Read));
+    }
+}
+
+fn aggregate_inputs(
+    device_inputs: Res<DeviceInputs>,
+    mut inputs: ResMut<Inputs>,
+    mut last_used: ResMut<LastUsedDevice>,
+) {
+    let mut merged = Inputs::default();
+    for (device, device_input) in device_inputs.sorted() {
+        merged.merge(device_input);
+        if device_input.is_active() && last_used.0 != *device {
+            last_used.0 = *device;
+        }
+    }
+    merged.update_edges(&inputs);
+    merged.frame = inputs.frame + 1;
+    *inputs = merged;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_map::Action;
+
+    fn aggregate(world: &mut World) {
+        let mut stage = SystemStage::single_threaded().with_system(aggregate_inputs);
+        stage.run(world);
+    }
+
+    #[test]
+    fn aggregate_merges_devices_and_tracks_the_last_used_one() {
+        let mut world = World::new();
+        world.insert_resource(Inputs::default());
+        world.insert_resource(LastUsedDevice(InputDevice::KeyboardMouse));
+        let gamepad = InputDevice::Gamepad(Gamepad::new(0));
+        let mut pad_inputs = Inputs::default();
+        pad_inputs.set_pressed(Action::Jump, true);
+        world.insert_resource(DeviceInputs([
+            (InputDevice::KeyboardMouse, Inputs::default()),
+            (gamepad, pad_inputs),
+        ].into_iter().collect()));
+
+        aggregate(&mut world);
+        let inputs = world.resource::<Inputs>();
+        assert!(inputs.just_pressed(Action::Jump));
+        assert_eq!(inputs.frame, 1);
+        assert_eq!(world.resource::<LastUsedDevice>().0, gamepad);
+
+        // Still held on the next frame
+        aggregate(&mut world);
+        let inputs = world.resource::<Inputs>();
+        assert!(inputs.pressed(Action::Jump));
+        assert!(!inputs.just_pressed(Action::Jump));
+        assert_eq!(inputs.frame, 2);
+    }
+
+    #[test]
+    fn devices_are_sorted_keyboard_first_then_gamepads_by_id() {
+        let devices = DeviceInputs([
+            InputDevice::Gamepad(Gamepad::new(3)),
+            InputDevice::KeyboardMouse,
+            InputDevice::Gamepad(Gamepad::new(0)),
+        ].into_iter().map(|device| (device, Inputs::default())).collect());
+        let order: Vec<InputDevice> = devices.sorted().into_iter().map(|(device, _)| *device).collect();
+        assert_eq!(order, [InputDevice::KeyboardMouse, InputDevice::Gamepad(Gamepad::new(0)), InputDevice::Gamepad(Gamepad::new(3))]);
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+
+use bevy::{prelude::*, input::InputSystem};
+use serde::{Deserialize, Serialize};
+
+use crate::ron_file;
+
+pub struct InputMapPlugin;
+
+const INPUT_MAP_PATH: &str = "settings/input_map.ron";
+
+/// Everything the player can do, independent of the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    MoveX,
+    MoveY,
+    Jump,
+    Dash,
+    Dive,
+    Grab,
+    CameraX,
+    CameraY,
+    Zoom,
+    Pause,
+}
+
+impl Action {
+    /// Actions read as buttons, with pressed / just pressed / just released states.
+    pub const BUTTONS: [Action; 5] = [Action::Jump, Action::Dash, Action::Dive, Action::Grab, Action::Pause];
+}
+
+/// A physical input that drives an action.
+/// Buttons read as 0 or 1 and axes as -1 to 1, so any binding works for any action.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    KeyAxis { negative: KeyCode, positive: KeyCode },
+    MouseButton(MouseButton),
+    MouseMotionX,
+    MouseMotionY,
+    MouseWheel,
+    GamepadButton(GamepadButtonType),
+    GamepadButtonAxis { negative: GamepadButtonType, positive: GamepadButtonType },
+    GamepadAxis(GamepadAxisType),
+}
+
+/// Bindings for every action, saved to `INPUT_MAP_PATH`.
+#[derive(Resource, Clone, Debug, Serialize, Deserialize)]
+pub struct InputMap {
+    pub actions: BTreeMap<Action, Vec<Binding>>,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        let mut map = Self { actions: BTreeMap::new() };
+        map.bind(Action::MoveX, Binding::KeyAxis { negative: KeyCode::A, positive: KeyCode::D });
+        map.bind(Action::MoveX, Binding::GamepadAxis(GamepadAxisType::LeftStickX));
+        map.bind(Action::MoveY, Binding::KeyAxis { negative: KeyCode::S, positive: KeyCode::W });
+        map.bind(Action::MoveY, Binding::GamepadAxis(GamepadAxisType::LeftStickY));
+        map.bind(Action::Jump, Binding::Key(KeyCode::Space));
+        map.bind(Action::Jump, Binding::GamepadButton(GamepadButtonType::South));
+        map.bind(Action::Dash, Binding::Key(KeyCode::LShift));
+        map.bind(Action::Dash, Binding::GamepadButton(GamepadButtonType::West));
+        map.bind(Action::Dive, Binding::Key(KeyCode::LControl));
+        map.bind(Action::Dive, Binding::GamepadButton(GamepadButtonType::East));
+        map.bind(Action::Grab, Binding::Key(KeyCode::E));
+        map.bind(Action::Grab, Binding::GamepadButton(GamepadButtonType::RightTrigger2));
+        map.bind(Action::CameraX, Binding::MouseMotionX);
+        map.bind(Action::CameraX, Binding::GamepadAxis(GamepadAxisType::RightStickX));
+        map.bind(Action::CameraY, Binding::MouseMotionY);
+        map.bind(Action::CameraY, Binding::GamepadAxis(GamepadAxisType::RightStickY));
+        map.bind(Action::Zoom, Binding::MouseWheel);
+        map.bind(Action::Zoom, Binding::GamepadButtonAxis { negative: GamepadButtonType::LeftTrigger, positive: GamepadButtonType::RightTrigger });
+        map.bind(Action::Pause, Binding::Key(KeyCode::Escape));
+        map.bind(Action::Pause, Binding::GamepadButton(GamepadButtonType::Start));
+        map
+    }
+}
+
+impl InputMap {
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.actions.get(&action).map_or(&[], |bindings| bindings.as_slice())
+    }
+
+    pub fn bind(&mut self, action: Action, binding: Binding) {
+        let bindings = self.actions.entry(action).or_default();
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    /// Replaces every binding of the same device kind (keyboard and mouse, or gamepad).
+    pub fn rebind(&mut self, action: Action, binding: Binding) {
+        let is_gamepad = binding.is_gamepad();
+        if let Some(bindings) = self.actions.get_mut(&action) {
+            bindings.retain(|b| b.is_gamepad() != is_gamepad);
+        }
+        self.bind(action, binding);
+    }
+
+    pub fn load() -> Option<Self> {
+        let mut map: InputMap = ron_file::load(INPUT_MAP_PATH)?;
+        // Actions added since the file was saved get their default bindings
+        for (action, bindings) in InputMap::default().actions {
+            map.actions.entry(action).or_insert(bindings);
+        }
+        Some(map)
+    }
+
+    pub fn save(&self) {
+        ron_file::save(INPUT_MAP_PATH, self, true);
+    }
+}
+
+impl Binding {
+    pub fn is_gamepad(&self) -> bool {
+        matches!(self, Binding::GamepadButton(_) | Binding::GamepadButtonAxis { .. } | Binding::GamepadAxis(_))
+    }
+}
+
+/// The value with the largest magnitude, so opposite inputs don't cancel out.
+pub fn strongest(values: impl Iterator<Item = f32>) -> f32 {
+    values.fold(0., |best, value| if value.abs() > best.abs() { value } else { best })
+}
+
+/// Set `action` to bind the next key, mouse button or gamepad button to it.
+#[derive(Resource, Default)]
+pub struct Rebinding {
+    pub action: Option<Action>,
+}
+
+impl Plugin for InputMapPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .insert_resource(InputMap::load().unwrap_or_default())
+        .init_resource::<Rebinding>()
+        .add_system_to_stage(CoreStage::PreUpdate, rebinding_system.after(InputSystem));
+    }
+}
+
+fn rebinding_system(
+    mut rebinding: ResMut<Rebinding>,
+    mut input_map: ResMut<InputMap>,
+    kb: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+) {
+    let action = if let Some(action) = rebinding.action { action } else { return };
+
+    let binding = if let Some(key) = kb.get_just_pressed().next() {
+        Some(Binding::Key(*key))
+    } else if let Some(button) = mouse_buttons.get_just_pressed().next() {
+        Some(Binding::MouseButton(*button))
+    } else {
+        gamepad_buttons.get_just_pressed().next().map(|button| Binding::GamepadButton(button.button_type))
+    };
+
+    if let Some(binding) = binding {
+        info!("Bound {:?} to {:?}", binding, action);
+        input_map.rebind(action, binding);
+        input_map.save();
+        rebinding.action = None;
+    }
+}
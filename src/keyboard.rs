@@ -1,59 +1,67 @@
-use bevy::{prelude::*, input::mouse::MouseMotion};
+use bevy::{prelude::*, input::{mouse::{MouseMotion, MouseWheel}, InputSystem}};
 
-use crate::gamepad::Inputs;
+use crate::{gamepad::Inputs, input_devices::{DeviceInputs, InputDevice, InputLabel}, input_map::{strongest, Action, Binding, InputMap}, settings::Settings};
 pub struct KeyboardControllerPlugin;
 
 impl Plugin for KeyboardControllerPlugin {
     fn build(&self, app: &mut App) {
         app
-        .add_system(keyboard_mouse_connections);
+        .add_system_to_stage(CoreStage::PreUpdate, keyboard_mouse_connections.label(InputLabel::Read).after(InputSystem));
     }
 }
 
 fn keyboard_mouse_connections(
-    mut commands: Commands,
     kb: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
     mut motion_evr: EventReader<MouseMotion>,
-    inputs: Res<Inputs>
+    mut wheel_evr: EventReader<MouseWheel>,
+    input_map: Res<InputMap>,
+    settings: Res<Settings>,
+    mut device_inputs: ResMut<DeviceInputs>
 ) {
-    let mut new_inputs = inputs.clone();
-    let mut horizontal = 0.;
-    let mut vertical = 0.;
-
-    if kb.pressed(KeyCode::W) {
-        horizontal = 1.;
-    }
-    if kb.pressed(KeyCode::S) {
-        horizontal = -1.;
+    let mut new_inputs = Inputs::default();
+    let mut mouse_delta = Vec2::ZERO;
+    // Several motion events can arrive in a single frame
+    for ev in motion_evr.iter() {
+        mouse_delta += ev.delta;
     }
-    if kb.pressed(KeyCode::A) {
-        vertical = -1.;
-    }
-    if kb.pressed(KeyCode::D) {
-        vertical = 1.;
+    mouse_delta *= settings.input.mouse_sensitivity;
+    if settings.input.mouse_invert_y {
+        mouse_delta.y = -mouse_delta.y;
     }
+    let mouse_wheel = wheel_evr.iter().map(|ev| ev.y).sum::<f32>() * settings.input.mouse_wheel_sensitivity;
 
-    new_inputs.jump_button = false;
-    if kb.pressed(KeyCode::Space) {
-        // println!("Jump");
-        new_inputs.jump_button = true;
-    }
+    let value = |action: Action| strongest(
+        input_map.bindings(action).iter().map(|binding| binding_value(binding, &kb, &mouse_buttons, mouse_delta, mouse_wheel))
+    );
+    let pressed = |action: Action| value(action).abs() >= 0.5;
 
-    new_inputs.dash_button = false;
-    if kb.pressed(KeyCode::LShift) {
-        new_inputs.dash_button = true;
+    for action in Action::BUTTONS {
+        new_inputs.set_pressed(action, pressed(action));
     }
 
-    new_inputs.player_movement = Vec2::new(vertical, horizontal);
+    new_inputs.player_movement = Vec2::new(value(Action::MoveX), value(Action::MoveY));
+    new_inputs.camera_movement = Vec2::new(value(Action::CameraX), value(Action::CameraY));
+    new_inputs.camera_zoom = value(Action::Zoom);
 
-    if motion_evr.is_empty() {
-        new_inputs.camera_movement = Vec2::new(0.,0.);
-    } else {
-        for ev in motion_evr.iter() {
-            new_inputs.camera_movement = Vec2::new(ev.delta.x, ev.delta.y);
-        }
-    }
+    device_inputs.0.insert(InputDevice::KeyboardMouse, new_inputs);
+}
 
-    commands.remove_resource::<Inputs>();
-    commands.insert_resource(new_inputs);
-}
\ No newline at end of file
+fn binding_value(
+    binding: &Binding,
+    kb: &Input<KeyCode>,
+    mouse_buttons: &Input<MouseButton>,
+    mouse_delta: Vec2,
+    mouse_wheel: f32
+) -> f32 {
+    let key_value = |key: &KeyCode| if kb.pressed(*key) { 1. } else { 0. };
+    match binding {
+        Binding::Key(key) => key_value(key),
+        Binding::KeyAxis { negative, positive } => key_value(positive) - key_value(negative),
+        Binding::MouseButton(button) => if mouse_buttons.pressed(*button) { 1. } else { 0. },
+        Binding::MouseMotionX => mouse_delta.x,
+        Binding::MouseMotionY => mouse_delta.y,
+        Binding::MouseWheel => mouse_wheel,
+        Binding::GamepadButton(_) | Binding::GamepadButtonAxis { .. } | Binding::GamepadAxis(_) => 0.,
+    }
+}
@@ -0,0 +1,111 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{camera_shake::{CameraShake, KNOCKBACK_SHAKE}, character_controller::CharacterController, player::{ground_query_filter, LedgeState, Player, PlayerLabel}, player_config::PlayerConfig};
+
+pub struct KnockbackPlugin;
+
+// The ground to stand up on is looked for from a little above the body,
+// not higher so platforms overhead aren't mistaken for it
+const GET_UP_RAY_START: f32 = 0.5;
+const GET_UP_REACH: f32 = 3.;
+// Gap left between the feet and the ground when standing up
+const GET_UP_SKIN: f32 = 0.02;
+
+/// Send to knock a player over.
+/// `impulse` is applied as a velocity change so it doesn't depend on the body's mass,
+/// `stun_time` falls back to `PlayerConfig::stun_time` when `None`.
+pub struct Knockback {
+    pub target: Entity,
+    pub impulse: Vec3,
+    pub stun_time: Option<f32>,
+}
+
+impl Plugin for KnockbackPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .add_event::<Knockback>()
+        .add_system(knockback_system.label(PlayerLabel::Knockback).after(PlayerLabel::Ground))
+        .add_system(stun_recovery_system.label(PlayerLabel::Knockback).after(knockback_system));
+    }
+}
+
+fn knockback_system(
+    mut commands: Commands,
+    mut events: EventReader<Knockback>,
+    mut shakes: EventWriter<CameraShake>,
+    mut player_query: Query<(&mut Player, &mut CharacterController, Option<&mut Velocity>)>,
+    config: Res<PlayerConfig>,
+) {
+    for ev in events.iter() {
+        if let Ok((mut player, mut controller, velocity)) = player_query.get_mut(ev.target) {
+            shakes.send(CameraShake { player: Some(ev.target), intensity: ev.impulse.length() * KNOCKBACK_SHAKE });
+            let was_stunned = player.is_stunned();
+            player.stun_timer = player.stun_timer.max(ev.stun_time.unwrap_or(config.stun_time));
+            player.is_dashing = false;
+            player.last_dash_time = -1.;
+            player.is_diving = false;
+            player.ledge = LedgeState::None;
+            // Tumble over in the direction of the hit
+            let spin = Vec3::Y.cross(ev.impulse) * config.knockback_spin;
+            if was_stunned {
+                if let Some(mut velocity) = velocity {
+                    velocity.linvel += ev.impulse;
+                    velocity.angvel += spin;
+                }
+                continue;
+            }
+
+            // Hand the body over to the physics engine until it recovers
+            controller.enabled = false;
+            commands.entity(ev.target)
+                .insert(RigidBody::Dynamic)
+                .insert(LockedAxes::empty())
+                .insert(GravityScale(config.gravity_scale))
+                .insert(Velocity {
+                    linvel: controller.velocity + ev.impulse,
+                    angvel: spin,
+                });
+        }
+    }
+}
+
+fn stun_recovery_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    rapier_context: Res<RapierContext>,
+    mut player_query: Query<(Entity, &mut Player, &mut CharacterController, &mut Transform)>,
+) {
+    for (entity, mut player, mut controller, mut transform) in player_query.iter_mut() {
+        if !player.is_stunned() {
+            continue;
+        }
+        player.stun_timer = (player.stun_timer - time.delta_seconds()).max(0.);
+        if player.is_stunned() {
+            continue;
+        }
+
+        // Stand back up facing the same way
+        let forward = transform.back() * Vec3::new(1., 0., 1.);
+        transform.rotation = Quat::IDENTITY;
+        if forward.length_squared() > 0.001 {
+            let target = transform.translation - forward;
+            transform.look_at(target, Vec3::Y);
+        }
+
+        // Put the feet of the upright shape on the ground under the body, so the
+        // controller doesn't start inside it. In the air the controller lands by itself
+        let ray_origin = transform.translation + Vec3::Y * GET_UP_RAY_START;
+        if let Some((_, toi)) = rapier_context.cast_ray(ray_origin, -Vec3::Y, GET_UP_REACH, true, ground_query_filter()) {
+            let ground = ray_origin.y - toi;
+            let feet = controller.shape.raw.compute_local_aabb().mins.y + controller.shape_offset.y;
+            transform.translation.y = transform.translation.y.max(ground - feet + GET_UP_SKIN);
+        }
+        controller.velocity = Vec3::ZERO;
+        controller.enabled = true;
+        commands.entity(entity)
+            .insert(RigidBody::KinematicPositionBased)
+            .insert(LockedAxes::ROTATION_LOCKED)
+            .insert(Velocity::zero());
+    }
+}
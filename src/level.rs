@@ -0,0 +1,503 @@
+use std::f32::consts::TAU;
+
+use bevy::{prelude::*, asset::{AssetLoader, LoadContext, LoadedAsset}, reflect::TypeUuid, utils::BoxedFuture};
+use bevy_rapier3d::prelude::*;
+use serde::Deserialize;
+
+use crate::{GROUND_COLLISION, camera::LockOnTarget, camera_rail::CameraRail, character_controller::{CharacterController, CharacterControllerLabel}, ghost::FinishLine, knockback::Knockback, player::{ground_query_filter, Player}, player_slots::PlayerSlot, replay::CurrentLevel, ron_file};
+
+pub struct LevelPlugin;
+
+// Players touching an obstacle are hit as if their shape was this much larger,
+// the character controller never quite lets them overlap
+const OBSTACLE_REACH: f32 = 1.15;
+
+/// A course, loaded from `levels/<name>.level.ron`.
+/// Angles are in radians, colors are bevy `Color`s like `Rgba(red: 1., green: 0., blue: 0., alpha: 1.)`.
+#[derive(Default, Deserialize, TypeUuid)]
+#[uuid = "0c4f6d52-8e1b-4a8f-b3d6-5e2a9c7f1b34"]
+#[serde(default)]
+pub struct Level {
+    pub geometry: Vec<LevelGeometry>,
+    // Where each player slot starts, slots past the end reuse them from the start
+    pub spawn_points: Vec<Vec3>,
+    pub lights: Vec<LevelLight>,
+    pub obstacles: Vec<LevelObstacle>,
+    pub finish_lines: Vec<LevelFinishLine>,
+    // Intro fly-over, played when the level is first loaded
+    pub camera_rail: Option<LevelCameraRail>,
+}
+
+/// A piece of static level geometry.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LevelGeometry {
+    pub mesh: LevelMesh,
+    #[serde(default)]
+    pub transform: LevelTransform,
+    // Relative to the transform, scaled with it. No collider makes it decoration only
+    #[serde(default)]
+    pub collider: Option<LevelCollider>,
+    #[serde(default)]
+    pub collision: LevelCollision,
+    // Primitives only, glTF models keep their own materials
+    #[serde(default = "default_color")]
+    pub color: Color,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum LevelMesh {
+    Plane(f32),
+    Cube(f32),
+    // Full size along each axis
+    Box(Vec3),
+    // Path of a glTF scene, e.g. "models/ramp.glb#Scene0"
+    Gltf(String),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LevelTransform {
+    pub translation: Vec3,
+    // Euler angles, applied in Y, X, Z order
+    pub rotation: Vec3,
+    pub scale: Vec3,
+}
+
+impl Default for LevelTransform {
+    fn default() -> Self {
+        Self { translation: Vec3::ZERO, rotation: Vec3::ZERO, scale: Vec3::ONE }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum LevelCollider {
+    Cuboid { half_extents: Vec3 },
+    Ball { radius: f32 },
+    Cylinder { half_height: f32, radius: f32 },
+    Capsule { half_height: f32, radius: f32 },
+}
+
+/// Which collision groups a collider is in and collides with.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub enum LevelCollision {
+    // Walkable ground, seen by the players and the ground checks
+    #[default]
+    Ground,
+    // Rapier group bits
+    Groups { memberships: u32, filters: u32 },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum LevelLight {
+    Point { translation: Vec3, color: Color, intensity: f32, shadows: bool },
+    // Lights along -Z turned by `rotation`, like the sun
+    Directional { rotation: Vec3, color: Color, illuminance: f32, shadows: bool },
+}
+
+/// Moving geometry that knocks players over when it hits them.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LevelObstacle {
+    pub geometry: LevelGeometry,
+    pub motion: ObstacleMotion,
+    // Speed the hit player is knocked away with
+    pub knockback: f32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum ObstacleMotion {
+    Static,
+    // Radians per second around `axis`
+    Spin { axis: Vec3, speed: f32 },
+    // Back and forth between the start and `offset` from it, every `period` seconds
+    Oscillate { offset: Vec3, period: f32 },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LevelFinishLine {
+    pub translation: Vec3,
+    pub half_extents: Vec3,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LevelCameraRail {
+    pub points: Vec<Vec3>,
+    pub look_at: Vec3,
+    pub duration: f32,
+}
+
+/// Handle to the level being played.
+#[derive(Resource)]
+pub struct LevelHandle(pub Handle<Level>);
+
+/// Where the players of the current level start, see `Level::spawn_points`.
+#[derive(Resource, Default)]
+pub struct SpawnPoints(pub Vec<Vec3>);
+
+/// Everything spawned from the level file, despawned when it is reloaded.
+#[derive(Component)]
+pub struct LevelEntity;
+
+#[derive(Component)]
+pub struct Obstacle {
+    pub motion: ObstacleMotion,
+    pub knockback: f32,
+    // Transform from the level file, the motion is applied on top of it
+    base: Transform,
+}
+
+/// Sent once the level is spawned, and again whenever it is hot-reloaded.
+pub struct LevelLoaded;
+
+impl SpawnPoints {
+    pub fn get(&self, index: usize) -> Vec3 {
+        if self.0.is_empty() {
+            Vec3::new(0., 1., index as f32 * 2.)
+        } else {
+            self.0[index % self.0.len()]
+        }
+    }
+}
+
+impl LevelTransform {
+    fn to_transform(&self) -> Transform {
+        Transform {
+            translation: self.translation,
+            rotation: Quat::from_euler(EulerRot::YXZ, self.rotation.y, self.rotation.x, self.rotation.z),
+            scale: self.scale,
+        }
+    }
+}
+
+impl LevelCollider {
+    fn collider(&self) -> Collider {
+        match *self {
+            LevelCollider::Cuboid { half_extents } => Collider::cuboid(half_extents.x, half_extents.y, half_extents.z),
+            LevelCollider::Ball { radius } => Collider::ball(radius),
+            LevelCollider::Cylinder { half_height, radius } => Collider::cylinder(half_height, radius),
+            LevelCollider::Capsule { half_height, radius } => Collider::capsule_y(half_height, radius),
+        }
+    }
+}
+
+impl LevelCollision {
+    fn groups(&self) -> CollisionGroups {
+        match *self {
+            LevelCollision::Ground => GROUND_COLLISION,
+            LevelCollision::Groups { memberships, filters } => {
+                CollisionGroups::new(Group::from_bits_truncate(memberships), Group::from_bits_truncate(filters))
+            }
+        }
+    }
+}
+
+fn default_color() -> Color {
+    Color::WHITE
+}
+
+#[derive(Default)]
+struct LevelLoader;
+
+impl AssetLoader for LevelLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let level: Level = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(level));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["level.ron"]
+    }
+}
+
+impl Plugin for LevelPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .add_asset::<Level>()
+        .init_asset_loader::<LevelLoader>()
+        .init_resource::<SpawnPoints>()
+        .add_event::<LevelLoaded>()
+        // Before the players are spawned on the spawn points
+        .add_startup_system_to_stage(StartupStage::PreStartup, load_level)
+        .add_system(reload_level)
+        .add_system(move_players_to_spawn_points.after(reload_level).before(CharacterControllerLabel::ReadOutput))
+        .add_system(obstacle_motion_system)
+        // Knocks players over in the same frame
+        .add_system(obstacle_hit_system.after(obstacle_motion_system).before(CharacterControllerLabel::ReadOutput));
+    }
+}
+
+/// Spawns the level straight from the file, so it is there on the first simulated frame
+/// and players never fall before it exists. The asset handle is only kept for hot-reloading.
+#[allow(clippy::too_many_arguments)]
+fn load_level(
+    mut commands: Commands,
+    ass: Res<AssetServer>,
+    level: Res<CurrentLevel>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut spawn_points: ResMut<SpawnPoints>,
+    mut loaded: EventWriter<LevelLoaded>,
+) {
+    let path = format!("levels/{}.level.ron", level.0);
+    if let Some(level) = ron_file::load_asset::<Level>(&path) {
+        spawn_level(&mut commands, &ass, &mut meshes, &mut materials, &level, true);
+        spawn_points.0 = level.spawn_points.clone();
+        loaded.send(LevelLoaded);
+    }
+    commands.insert_resource(LevelHandle(ass.load(path)));
+}
+
+#[allow(clippy::too_many_arguments)]
+fn reload_level(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<Level>>,
+    levels: Res<Assets<Level>>,
+    handle: Res<LevelHandle>,
+    ass: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut spawn_points: ResMut<SpawnPoints>,
+    level_entities: Query<Entity, With<LevelEntity>>,
+    mut loaded: EventWriter<LevelLoaded>,
+) {
+    // `load_level` already spawned what the handle first loads
+    let modified = events.iter().any(|ev| matches!(ev, AssetEvent::Modified { handle: modified } if *modified == handle.0));
+    if !modified {
+        return;
+    }
+    let level = if let Some(level) = levels.get(&handle.0) { level } else { return };
+
+    for entity in level_entities.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    spawn_level(&mut commands, &ass, &mut meshes, &mut materials, level, false);
+    spawn_points.0 = level.spawn_points.clone();
+    loaded.send(LevelLoaded);
+}
+
+/// `first_load` plays the intro camera rail.
+fn spawn_level(
+    commands: &mut Commands,
+    ass: &AssetServer,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    level: &Level,
+    first_load: bool,
+) {
+    for geometry in level.geometry.iter() {
+        spawn_geometry(commands, ass, meshes, materials, geometry);
+    }
+
+    for obstacle in level.obstacles.iter() {
+        let entity = spawn_geometry(commands, ass, meshes, materials, &obstacle.geometry);
+        commands.entity(entity)
+            .insert(RigidBody::KinematicPositionBased)
+            .insert(Obstacle {
+                motion: obstacle.motion.clone(),
+                knockback: obstacle.knockback,
+                base: obstacle.geometry.transform.to_transform(),
+            });
+    }
+
+    for finish in level.finish_lines.iter() {
+        commands.spawn(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Box::new(finish.half_extents.x * 2., finish.half_extents.y * 2., finish.half_extents.z * 2.))),
+            material: materials.add(StandardMaterial {
+                base_color: Color::rgba(1.0, 0.8, 0.1, 0.3),
+                alpha_mode: AlphaMode::Blend,
+                ..default()
+            }),
+            transform: Transform::from_translation(finish.translation),
+            ..default()
+        }).insert(FinishLine {
+            half_extents: finish.half_extents
+        })
+        .insert(LockOnTarget)
+        .insert(LevelEntity);
+    }
+
+    for light in level.lights.iter() {
+        match *light {
+            LevelLight::Point { translation, color, intensity, shadows } => {
+                commands.spawn(PointLightBundle {
+                    point_light: PointLight {
+                        color,
+                        intensity,
+                        shadows_enabled: shadows,
+                        ..default()
+                    },
+                    transform: Transform::from_translation(translation),
+                    ..default()
+                }).insert(LevelEntity);
+            }
+            LevelLight::Directional { rotation, color, illuminance, shadows } => {
+                commands.spawn(DirectionalLightBundle {
+                    directional_light: DirectionalLight {
+                        color,
+                        illuminance,
+                        shadows_enabled: shadows,
+                        ..default()
+                    },
+                    transform: Transform::from_rotation(Quat::from_euler(EulerRot::YXZ, rotation.y, rotation.x, rotation.z)),
+                    ..default()
+                }).insert(LevelEntity);
+            }
+        }
+    }
+
+    if let Some(rail) = &level.camera_rail {
+        commands.spawn(CameraRail {
+            points: rail.points.clone(),
+            look_at: rail.look_at,
+            duration: rail.duration,
+            // Don't replay the intro on every hot-reload
+            autoplay: first_load,
+        }).insert(LevelEntity);
+    }
+}
+
+fn spawn_geometry(
+    commands: &mut Commands,
+    ass: &AssetServer,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    geometry: &LevelGeometry,
+) -> Entity {
+    let transform = geometry.transform.to_transform();
+    let mesh = match &geometry.mesh {
+        LevelMesh::Plane(size) => Mesh::from(shape::Plane { size: *size }),
+        LevelMesh::Cube(size) => Mesh::from(shape::Cube { size: *size }),
+        LevelMesh::Box(size) => Mesh::from(shape::Box::new(size.x, size.y, size.z)),
+        LevelMesh::Gltf(path) => {
+            let scene: Handle<Scene> = ass.load(path);
+            let entity = commands.spawn(SceneBundle {
+                scene,
+                transform,
+                ..default()
+            }).insert(LevelEntity).id();
+            insert_collider(commands, entity, geometry);
+            return entity;
+        }
+    };
+    let entity = commands.spawn(PbrBundle {
+        mesh: meshes.add(mesh),
+        material: materials.add(geometry.color.into()),
+        transform,
+        ..default()
+    }).insert(LevelEntity).id();
+    insert_collider(commands, entity, geometry);
+    entity
+}
+
+fn insert_collider(commands: &mut Commands, entity: Entity, geometry: &LevelGeometry) {
+    if let Some(collider) = &geometry.collider {
+        commands.entity(entity)
+            .insert(collider.collider())
+            .insert(geometry.collision.groups());
+    }
+}
+
+/// Puts the players back at the start whenever the level is (re)loaded.
+fn move_players_to_spawn_points(
+    mut loaded: EventReader<LevelLoaded>,
+    spawn_points: Res<SpawnPoints>,
+    mut player_query: Query<(&PlayerSlot, &mut Transform, &mut CharacterController)>,
+) {
+    if loaded.iter().count() == 0 {
+        return;
+    }
+    for (slot, mut transform, mut controller) in player_query.iter_mut() {
+        transform.translation = spawn_points.get(slot.index);
+        controller.velocity = Vec3::ZERO;
+    }
+}
+
+fn obstacle_motion_system(
+    time: Res<Time>,
+    mut obstacle_query: Query<(&Obstacle, &mut Transform)>,
+) {
+    let elapsed = time.elapsed_seconds();
+    for (obstacle, mut transform) in obstacle_query.iter_mut() {
+        *transform = obstacle.base;
+        match obstacle.motion {
+            ObstacleMotion::Static => {}
+            ObstacleMotion::Spin { axis, speed } => {
+                transform.rotation = Quat::from_axis_angle(axis.normalize_or_zero(), speed * elapsed) * obstacle.base.rotation;
+            }
+            ObstacleMotion::Oscillate { offset, period } => {
+                let t = 0.5 - 0.5 * (elapsed / period.max(f32::EPSILON) * TAU).cos();
+                transform.translation = obstacle.base.translation + offset * t;
+            }
+        }
+    }
+}
+
+/// Knocks players away from the obstacles they touch.
+fn obstacle_hit_system(
+    rapier_context: Res<RapierContext>,
+    player_query: Query<(Entity, &Player, &Transform, &CharacterController)>,
+    obstacle_query: Query<(&Obstacle, &GlobalTransform)>,
+    mut knockbacks: EventWriter<Knockback>,
+) {
+    for (entity, player, transform, controller) in player_query.iter() {
+        if player.is_stunned() || !controller.enabled {
+            continue;
+        }
+        let mut shape = controller.shape.clone();
+        shape.set_scale(Vec3::splat(OBSTACLE_REACH), 8);
+        let mut hit = None;
+        rapier_context.intersections_with_shape(
+            transform.translation + transform.rotation * controller.shape_offset, transform.rotation, &shape, ground_query_filter(),
+            |collider| {
+                if obstacle_query.contains(collider) {
+                    hit = Some(collider);
+                    false
+                } else {
+                    true
+                }
+            },
+        );
+        if let Some((obstacle, obstacle_transform)) = hit.and_then(|hit| obstacle_query.get(hit).ok()) {
+            let away = ((transform.translation - obstacle_transform.translation()) * Vec3::new(1., 0., 1.)).normalize_or_zero();
+            knockbacks.send(Knockback {
+                target: entity,
+                impulse: (away + Vec3::Y * 0.5) * obstacle.knockback,
+                stun_time: None,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawn_points_wrap_around() {
+        let spawn_points = SpawnPoints(vec![Vec3::X, Vec3::Y]);
+        assert_eq!(spawn_points.get(0), Vec3::X);
+        assert_eq!(spawn_points.get(1), Vec3::Y);
+        assert_eq!(spawn_points.get(2), Vec3::X);
+    }
+
+    #[test]
+    fn no_spawn_points_lines_players_up() {
+        let spawn_points = SpawnPoints::default();
+        assert_eq!(spawn_points.get(0), Vec3::new(0., 1., 0.));
+        assert_eq!(spawn_points.get(3), Vec3::new(0., 1., 6.));
+    }
+
+    #[test]
+    fn default_level_parses() {
+        let level: Level = ron::from_str(include_str!("../assets/levels/default.level.ron")).unwrap();
+        assert!(!level.geometry.is_empty());
+        assert!(!level.spawn_points.is_empty());
+        assert!(!level.finish_lines.is_empty());
+    }
+}
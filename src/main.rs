@@ -1,19 +1,47 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-use bevy::{prelude::*, render::settings::{WgpuSettings, WgpuFeatures}, diagnostic::{FrameTimeDiagnosticsPlugin}, window::PresentMode};
+use bevy::{prelude::*, app::ScheduleRunnerPlugin, render::settings::{WgpuSettings, WgpuFeatures}, diagnostic::{FrameTimeDiagnosticsPlugin}, window::PresentMode, winit::WinitPlugin};
 use bevy_rapier3d::prelude::*;
 use bevy_editor_pls::EditorPlugin;
 use camera::CameraPlugin;
+use camera_rail::CameraRailPlugin;
+use camera_shake::CameraShakePlugin;
+use character_controller::CharacterControllerPlugin;
 use debug_mode::DebugModePlugin;
-use gamepad::{GamepadControllerPlugin, Inputs};
+use gamepad::GamepadControllerPlugin;
+use ghost::GhostPlugin;
+use grab::GrabPlugin;
+use input_devices::InputDevicesPlugin;
+use input_map::InputMapPlugin;
 use keyboard::KeyboardControllerPlugin;
+use knockback::KnockbackPlugin;
+use level::LevelPlugin;
 use player::PlayerPlugin;
+use player_config::PlayerConfigPlugin;
+use player_slots::PlayerSlotsPlugin;
+use replay::ReplayPlugin;
+use settings::SettingsPlugin;
+use spectator::SpectatorPlugin;
 
 mod camera;
+mod camera_rail;
+mod camera_shake;
 mod gamepad;
+mod ghost;
+mod input_devices;
+mod input_map;
 mod keyboard;
+mod level;
 mod player;
+mod player_config;
+mod player_slots;
+mod replay;
+mod ron_file;
+mod settings;
+mod spectator;
 mod character_controller;
 mod debug_mode;
+mod grab;
+mod knockback;
 
 pub const HEIGHT: f32 = 720.0;
 pub const RATIO: f32 = 16. / 9.;
@@ -21,8 +49,15 @@ pub const RATIO: f32 = 16. / 9.;
 const GROUND_COLLISION: CollisionGroups = CollisionGroups::new(Group::GROUP_1, Group::GROUP_10);
 
 fn main() {
+    // --headless runs without a window or renderer, e.g. to check replays
+    let headless = std::env::args().any(|arg| arg == "--headless");
+    game_app(headless).run();
+}
+
+fn game_app(headless: bool) -> App {
     let wpu_settings: WgpuSettings = WgpuSettings {
         features: WgpuFeatures::POLYGON_MODE_LINE,
+        backends: if headless { None } else { WgpuSettings::default().backends },
         ..default()
     };
     let window_plugin: WindowPlugin = WindowPlugin {
@@ -34,92 +69,59 @@ fn main() {
             present_mode: PresentMode::AutoVsync,
             ..default()
         },
+        // Nothing would ever be open
+        exit_on_all_closed: !headless,
         ..default()
     };
-    App::new()
+    let mut plugins = DefaultPlugins.set(window_plugin).set(AssetPlugin {
+        // Hot-reload config files while tuning
+        watch_for_changes: true,
+        ..default()
+    });
+    if headless {
+        plugins = plugins.disable::<WinitPlugin>();
+    }
+
+    let mut app = App::new();
+    app
     .register_type::<Group>()
     .insert_resource(wpu_settings)
     .insert_resource(AmbientLight {
         color: Color::rgb(0.5, 0.5, 0.5),
         brightness: 2.5
     })
-    .insert_resource(Inputs::default())
-    .add_plugins(DefaultPlugins.set(window_plugin))
-    .add_plugin(EditorPlugin)
+    .add_plugins(plugins);
+    if headless {
+        app.add_plugin(ScheduleRunnerPlugin);
+    } else {
+        app.add_plugin(EditorPlugin);
+    }
+    app
+    .add_plugin(PlayerConfigPlugin)
+    .add_plugin(LevelPlugin)
+    .add_plugin(CharacterControllerPlugin)
     .add_plugin(PlayerPlugin)
+    .add_plugin(PlayerSlotsPlugin)
+    .add_plugin(GrabPlugin)
+    .add_plugin(KnockbackPlugin)
     .add_plugin(CameraPlugin)
-    //.add_plugin(GamepadControllerPlugin)
+    .add_plugin(CameraRailPlugin)
+    .add_plugin(CameraShakePlugin)
+    .add_plugin(SpectatorPlugin)
+    .add_plugin(SettingsPlugin)
+    .add_plugin(InputMapPlugin)
+    .add_plugin(InputDevicesPlugin)
+    .add_plugin(GamepadControllerPlugin)
     .add_plugin(KeyboardControllerPlugin)
-    .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
-    .add_plugin(RapierDebugRenderPlugin::default())
+    .add_plugin(ReplayPlugin)
+    .add_plugin(GhostPlugin)
+    .add_plugin(RapierPhysicsPlugin::<NoUserData>::default());
+    if !headless {
+        app.add_plugin(RapierDebugRenderPlugin::default());
+    }
+    app
     // .add_plugin(LogDiagnosticsPlugin::default())
     .add_plugin(FrameTimeDiagnosticsPlugin::default())
-    .add_plugin(DebugModePlugin)
-    .add_startup_system(setup)
-    .run();
+    .add_plugin(DebugModePlugin);
+    app
 }
-
-fn setup(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    asset_server: Res<AssetServer>,
-) {
-    // plane
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Plane { size: 25.0 })),
-        material: materials.add(Color::rgb(0.3, 0.5, 0.3).into()),
-        transform: Transform::from_xyz(0.0, 0.0, 0.0),
-        ..default()
-    }).insert(Collider::cuboid(12.5, 0.1, 12.5))
-    .insert(GROUND_COLLISION);
-
-    // plane
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Plane { size: 25.0 })),
-        material: materials.add(Color::rgb(0.3, 0.5, 0.3).into()),
-        transform: Transform::from_xyz(20.0, 0.0, 0.0).with_rotation(Quat::from_rotation_z(0.15)),
-        ..default()
-    }).insert(Collider::cuboid(12.5, 0.1, 12.5))
-    .insert(GROUND_COLLISION);
-
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Cube { size: 25. })),
-        material: materials.add(Color::rgb(0.3, 0.5, 0.3).into()),
-        transform: Transform::from_xyz(50.0, -10.5, 15.0),
-        ..default()
-    }).insert(Collider::cuboid(12.5, 12.5, 12.5))
-    .insert(GROUND_COLLISION);
-
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Cube { size: 25. })),
-        material: materials.add(Color::rgb(0.3, 0.5, 0.3).into()),
-        transform: Transform::from_xyz(50.0, -10.5, -35.0),
-        ..default()
-    }).insert(Collider::cuboid(12.5, 12.5, 12.5))
-    .insert(GROUND_COLLISION);
-
-    // light
-    commands.spawn(PointLightBundle {
-        point_light: PointLight {
-            intensity: 5000.0,
-            shadows_enabled: true,
-            ..default()
-        },
-        transform: Transform::from_xyz(4.0, 8.0, 4.0),
-        ..default()
-    });
-
-    // cube
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Cube { size: 0.5 })),
-        material: materials.add(Color::rgb(0.8, 0.7, 0.6).into()),
-        transform: Transform::from_xyz(10.0, 0.75, 0.0),
-        ..default()
-    });
-
-    commands.spawn(DynamicSceneBundle{
-        scene: asset_server.load("scenes/scene.scn.ron"),
-        ..default()
-    });
-}
\ No newline at end of file
@@ -1,7 +1,8 @@
 use bevy::prelude::*;
-use bevy_rapier3d::{prelude::*, rapier::prelude::Group};
+use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::{camera::{CameraFollow, MainCamera}, gamepad::Inputs};
+use crate::{GROUND_COLLISION, camera::{LockOnTarget, MainCamera}, camera_shake::{CameraShake, DASH_SHAKE, LANDING_SHAKE, LANDING_SHAKE_HEIGHT}, character_controller::{CharacterController, CharacterControllerLabel}, gamepad::Inputs, input_map::Action, level::SpawnPoints, player_config::{PlayerConfig, WallJumpReset}, player_slots::PlayerSlot, spectator::SpectatorTarget};
 
 pub struct PlayerPlugin;
 
@@ -11,8 +12,46 @@ pub struct Player {
     pub jumps_without_ground: i8,
     pub is_grounded: bool,
     pub is_dashing: bool,
-    pub dashes: i8,
-    pub last_dash_time: f32
+    // Dashes available right now, refilled over time or on landing
+    pub dash_charges: u8,
+    pub dash_recharge_timer: f32,
+    pub dash_cooldown_timer: f32,
+    pub air_dashes: u8,
+    pub last_dash_time: f32,
+    // Time left to still jump from the ground after leaving it
+    pub coyote_timer: f32,
+    // Time left for a jump press to fire once a jump is possible
+    pub jump_buffer_timer: f32,
+    pub is_diving: bool,
+    // Time left on the ground before getting up from a dive
+    pub dive_timer: f32,
+    pub grabbing: Option<Entity>,
+    // The player holding this one, its pull adds to the movement instead of being overwritten
+    pub grabbed_by: Option<Entity>,
+    // 0 to 1, drains while holding a grab
+    pub grab_stamina: f32,
+    // 0 to 1, filled by mashing while grabbed, breaks free when full
+    pub grab_struggle: f32,
+    // Time left knocked over, input is ignored meanwhile
+    pub stun_timer: f32,
+    // Normal of the wall the player is sliding on, pointing away from it
+    pub wall_normal: Option<Vec3>,
+    // Time left before the stick steers again after a wall jump
+    pub wall_jump_timer: f32,
+    pub ledge: LedgeState,
+    // Time left before a ledge can be grabbed again after dropping off
+    pub ledge_regrab_timer: f32,
+    // Highest point since leaving the ground, to shake the camera on hard landings
+    pub air_peak_height: f32
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LedgeState {
+    None,
+    // Holding on to `point`, the top edge of a wall facing `normal`
+    Hanging { point: Vec3, normal: Vec3 },
+    // Climbing from the hang position onto the ledge
+    Mantling { from: Vec3, to: Vec3, elapsed: f32 },
 }
 
 impl Player {
@@ -22,35 +61,111 @@ impl Player {
             is_jumping: false,
             is_grounded: false,
             is_dashing: false,
-            dashes: 0,
-            last_dash_time: -1.
+            dash_charges: 0,
+            dash_recharge_timer: 0.,
+            dash_cooldown_timer: 0.,
+            air_dashes: 0,
+            last_dash_time: -1.,
+            coyote_timer: 0.,
+            jump_buffer_timer: 0.,
+            is_diving: false,
+            dive_timer: 0.,
+            grabbing: None,
+            grabbed_by: None,
+            grab_stamina: 1.,
+            grab_struggle: 0.,
+            stun_timer: 0.,
+            wall_normal: None,
+            wall_jump_timer: 0.,
+            ledge: LedgeState::None,
+            ledge_regrab_timer: 0.,
+            air_peak_height: 0.
         }
     }
-}
 
-const PLAYER_SPEED: f32 = 10.;
-const JUMP_HEIGHT: f32 = 7.5;
-const DASH_IMPULSE: f32 = 5.5;
-const DASH_TIME: f32 = 0.1;
+    pub fn is_on_ledge(&self) -> bool {
+        self.ledge != LedgeState::None
+    }
+
+    pub fn is_stunned(&self) -> bool {
+        self.stun_timer > 0.
+    }
+}
 
+/// Marks where a player is heading, the player it belongs to.
 #[derive(Component)]
-pub struct PlayerMovementIndicator;
+pub struct PlayerMovementIndicator(pub Entity);
 
 #[derive(Resource)]
 struct Animations(Vec<Handle<AnimationClip>>);
 
+/// Animation the model should play, set from the player state.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PlayerAnimation {
+    Idle,
+    Jump,
+    Run,
+    Dive,
+    Hang,
+    Mantle,
+}
+
+impl PlayerAnimation {
+    /// Index into `Animations`
+    fn clip(&self) -> usize {
+        match self {
+            // The model has no dive, hang or mantle clips yet, so reuse the closest ones
+            PlayerAnimation::Idle | PlayerAnimation::Hang => 0,
+            PlayerAnimation::Jump | PlayerAnimation::Dive | PlayerAnimation::Mantle => 1,
+            PlayerAnimation::Run => 2,
+        }
+    }
+
+    fn repeat(&self) -> bool {
+        matches!(self, PlayerAnimation::Idle | PlayerAnimation::Run | PlayerAnimation::Hang)
+    }
+}
+
+/// The player systems run in this order, between `CharacterControllerLabel::ReadOutput` and `Move`:
+/// `Ground` reads what the last move touched, `Knockback` and `Grab` update who is stunned or held,
+/// then `Velocity` sets `CharacterController.velocity`. Forces from outside like the grab pull are added after it.
+#[derive(SystemLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PlayerLabel {
+    Ground,
+    Knockback,
+    Grab,
+    Velocity,
+}
+
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
         app
         .add_startup_system(player_spawn_system)
-        .add_system(player_movement_system)
-        .add_system(player_jump_system)
-        .add_system(player_dash_system)
-        .add_system(check_is_grounded)
-        .add_system(animation_controller_system);
+        .add_system(apply_player_config_system.before(CharacterControllerLabel::Move))
+        .add_system(check_is_grounded.label(PlayerLabel::Ground).after(CharacterControllerLabel::ReadOutput))
+        .add_system(check_is_on_wall.label(PlayerLabel::Ground).after(check_is_grounded))
+        .add_system(player_movement_system.label(PlayerLabel::Velocity).after(PlayerLabel::Grab).before(CharacterControllerLabel::Move))
+        .add_system(player_jump_system.label(PlayerLabel::Velocity).after(player_movement_system).before(CharacterControllerLabel::Move))
+        .add_system(player_gravity_system.label(PlayerLabel::Velocity).after(player_jump_system).before(CharacterControllerLabel::Move))
+        .add_system(player_dash_system.label(PlayerLabel::Velocity).after(player_gravity_system).before(CharacterControllerLabel::Move))
+        .add_system(player_dive_system.label(PlayerLabel::Velocity).after(player_dash_system).before(CharacterControllerLabel::Move))
+        .add_system(player_ledge_system.label(PlayerLabel::Velocity).after(player_dive_system).before(CharacterControllerLabel::Move))
+        .add_system(player_animation_system.after(CharacterControllerLabel::Move))
+        .add_system(animation_controller_system.after(player_animation_system));
     }
 }
 
+// Steeper than this counts as a wall, as the y of the surface normal
+const WALL_MAX_NORMAL_Y: f32 = 0.3;
+const WALL_CHECK_RADIUS: f32 = 0.3;
+// Height of the hands above the player origin when hanging
+const LEDGE_HANDS_HEIGHT: f32 = 1.;
+// How far past the wall the player ends up after mantling
+const LEDGE_STEP_IN: f32 = 0.45;
+// Height of the player origin above the feet
+const PLAYER_STAND_HEIGHT: f32 = 1.9;
+const LEDGE_REGRAB_TIME: f32 = 0.3;
+
 const PLAYER_MODEL: &str = "models\\stylized_low_poly_animated_character.glb";
 const PLAYER_MODEL_SCENE: &str = "#Scene0";
 
@@ -66,38 +181,6 @@ fn player_spawn_system(
         ass.load(PLAYER_MODEL.to_string() + "#Animation1"),
         ass.load(PLAYER_MODEL.to_string() + "#Animation2"),
     ]));
-    let my_gltf: Handle<Scene> = ass.load(PLAYER_MODEL.to_string() + PLAYER_MODEL_SCENE);
-
-    // to position our 3d model, simply use the Transform
-    // in the SceneBundle
-    commands.spawn(SceneBundle {
-        scene: my_gltf,
-        transform: Transform::from_xyz(0., 1., 0.).with_scale(Vec3::new(1.5,1.5,1.5)).with_rotation(Quat::from_rotation_y(45.)),
-        ..Default::default()
-    }).insert(Player::default())
-    .insert(CameraFollow)
-    .insert(RigidBody::Dynamic)
-    .insert(Velocity {
-        linvel: Vec3::ZERO,
-        angvel: Vec3::ZERO,
-    })
-    .insert(ExternalImpulse {
-        impulse: Vec3::ZERO,
-        torque_impulse: Vec3::ZERO,
-    })
-    .with_children(|children| {
-        children.spawn(PbrBundle::default())
-            .insert(Collider::ball(0.25))
-            .insert(TransformBundle::from(Transform::from_xyz(0.0, -1., 0.0)))
-            .insert(CollisionGroups::new(bevy_rapier3d::geometry::Group::GROUP_10, bevy_rapier3d::geometry::Group::GROUP_1));
-        children.spawn(PbrBundle::default())
-            .insert(Collider::cuboid(0.15, 0.65, 0.15))
-            .insert(TransformBundle::from(Transform::from_xyz(0.0, 0., 0.0)))
-            .insert(CollisionGroups::new(bevy_rapier3d::geometry::Group::GROUP_10, bevy_rapier3d::geometry::Group::GROUP_1));
-    })
-    .insert(GravityScale(2.))
-    .insert(LockedAxes::ROTATION_LOCKED)
-    .insert(CollisionGroups::new(bevy_rapier3d::geometry::Group::GROUP_10, bevy_rapier3d::geometry::Group::GROUP_1));
 
     // enemy
     commands.spawn(PbrBundle {
@@ -126,122 +209,471 @@ fn player_spawn_system(
         });
     })
     .insert(RigidBody::Dynamic)
-    .insert(Collider::cylinder(0.5, 0.5));
+    // Read by the grab spring for damping
+    .insert(Velocity::zero())
+    .insert(Collider::cylinder(0.5, 0.5))
+    .insert(LockOnTarget)
+    .insert(SpectatorTarget);
+}
+
+/// The animated character model, for anything that should look like a player.
+pub fn player_model(ass: &AssetServer, transform: Transform) -> SceneBundle {
+    let my_gltf: Handle<Scene> = ass.load(PLAYER_MODEL.to_string() + PLAYER_MODEL_SCENE);
+
+    // to position our 3d model, simply use the Transform
+    // in the SceneBundle
+    SceneBundle {
+        scene: my_gltf,
+        transform: transform.with_scale(Vec3::new(1.5,1.5,1.5)),
+        ..Default::default()
+    }
+}
+
+/// Spawns a player with its model, colliders and movement indicator.
+pub fn spawn_player(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    ass: &AssetServer,
+    config: &PlayerConfig,
+    position: Vec3
+) -> Entity {
+    let player = commands.spawn(player_model(ass, Transform::from_translation(position).with_rotation(Quat::from_rotation_y(45.))))
+    .insert(PlayerAnimation::Idle)
+    .insert(Player {
+        dash_charges: config.dash_charges,
+        ..Player::default()
+    })
+    .insert(Inputs::default())
+    .insert(LockOnTarget)
+    .insert(SpectatorTarget)
+    // Covers the scaled ball and body colliders below
+    .insert(CharacterController::new(Collider::capsule_y(1.05, 0.375), Vec3::new(0., -0.45, 0.)))
+    .with_children(|children| {
+        children.spawn(PbrBundle::default())
+            .insert(Collider::ball(0.25))
+            .insert(TransformBundle::from(Transform::from_xyz(0.0, -1., 0.0)))
+            .insert(CollisionGroups::new(bevy_rapier3d::geometry::Group::GROUP_10, bevy_rapier3d::geometry::Group::GROUP_1));
+        children.spawn(PbrBundle::default())
+            .insert(Collider::cuboid(0.15, 0.65, 0.15))
+            .insert(TransformBundle::from(Transform::from_xyz(0.0, 0., 0.0)))
+            .insert(CollisionGroups::new(bevy_rapier3d::geometry::Group::GROUP_10, bevy_rapier3d::geometry::Group::GROUP_1));
+    })
+    .insert(CollisionGroups::new(bevy_rapier3d::geometry::Group::GROUP_10, bevy_rapier3d::geometry::Group::GROUP_1))
+    .id();
 
     commands.spawn(PbrBundle {
         mesh: meshes.add(Mesh::from(shape::Cube { size: 0.25 })),
         material: materials.add(Color::rgb(1.0, 1.0, 1.0).into()),
-        transform: Transform::from_xyz(0.0, 0.0, 0.0),
+        transform: Transform::from_translation(position),
         ..default()
-    }).insert(PlayerMovementIndicator);
+    }).insert(PlayerMovementIndicator(player));
+
+    player
+}
+
+fn apply_player_config_system(
+    config: Res<PlayerConfig>,
+    mut player_query: Query<&mut CharacterController, With<Player>>
+) {
+    for mut controller in player_query.iter_mut() {
+        if config.is_changed() || controller.is_added() {
+            controller.snap_to_ground = config.ground_check_distance;
+        }
+    }
 }
 
 fn player_jump_system(
-    mut player_query: Query<(&mut Velocity, &mut Player), With<Player>>,
-    inputs: Res<Inputs>
+    time: Res<Time>,
+    mut player_query: Query<(&mut CharacterController, &mut Player, &Inputs), With<Player>>,
+    config: Res<PlayerConfig>
 ) {
-    if inputs.jump_button {
-        for (mut velocity, mut player) in player_query.iter_mut() {
-            if !player.is_jumping || player.jumps_without_ground < 1 {
-                velocity.linvel = Vec3::new(velocity.linvel.x,JUMP_HEIGHT, velocity.linvel.z);
-                player.is_jumping = true;
-                player.jumps_without_ground = player.jumps_without_ground + 1;
+    for (mut controller, mut player, inputs) in player_query.iter_mut() {
+        if player.is_stunned() {
+            player.jump_buffer_timer = 0.;
+            continue;
+        }
+        if inputs.just_pressed(Action::Jump) {
+            player.jump_buffer_timer = config.jump_buffer_time;
+        } else {
+            player.jump_buffer_timer = (player.jump_buffer_timer - time.delta_seconds()).max(0.);
+        }
+
+        // Short hop when jump is let go while still going up
+        if inputs.just_released(Action::Jump) && player.is_jumping && controller.velocity.y > 0. {
+            controller.velocity.y *= config.jump_cut_multiplier;
+        }
+
+        if player.jump_buffer_timer <= 0. || player.is_diving || player.is_on_ledge() {
+            continue;
+        }
+
+        let can_ground_jump = !player.is_jumping && player.coyote_timer > 0.;
+        // Air jumps only once the coyote window is over, walking off a ledge keeps them
+        let can_air_jump = player.coyote_timer <= 0. && player.jumps_without_ground < config.max_jumps_without_ground;
+        if let (false, Some(wall_normal)) = (can_ground_jump, player.wall_normal) {
+            // Kick off the wall, the stick can't pull back into it for a moment
+            controller.velocity = wall_normal * config.wall_jump_push + Vec3::Y * config.wall_jump_height;
+            player.is_jumping = true;
+            player.wall_jump_timer = config.wall_jump_lock_time;
+            player.wall_normal = None;
+            player.jump_buffer_timer = 0.;
+            match config.wall_jump_reset {
+                WallJumpReset::OnWallJump => player.jumps_without_ground = 0,
+                WallJumpReset::Never => player.jumps_without_ground += 1,
+                WallJumpReset::OnWallTouch => {}
+            }
+        } else if can_ground_jump || can_air_jump {
+            controller.velocity.y = config.jump_height;
+            player.is_jumping = true;
+            if !can_ground_jump {
+                player.jumps_without_ground += 1;
             }
+            player.coyote_timer = 0.;
+            player.jump_buffer_timer = 0.;
+        }
+    }
+}
+
+fn player_gravity_system(
+    mut player_query: Query<(&mut CharacterController, &Player, &Inputs), With<Player>>,
+    config: Res<PlayerConfig>
+) {
+    for (mut controller, player, inputs) in player_query.iter_mut() {
+        let vertical_speed = controller.velocity.y;
+        let multiplier = if player.is_jumping && inputs.pressed(Action::Jump) && vertical_speed.abs() < config.apex_threshold {
+            config.apex_gravity_multiplier
+        } else if !player.is_grounded && vertical_speed < 0. {
+            config.fall_gravity_multiplier
+        } else {
+            1.
+        };
+        controller.gravity_scale = config.gravity_scale * multiplier;
+
+        if player.wall_normal.is_some() {
+            controller.velocity.y = controller.velocity.y.max(-config.wall_slide_speed);
         }
     }
 }
 
 fn player_dash_system(
     time: Res<Time>,
-    mut player_query: Query<(&Transform, &mut ExternalImpulse, &mut Player), With<Player>>,
-    inputs: Res<Inputs>
+    mut player_query: Query<(Entity, &Transform, &mut CharacterController, &mut Player, &Inputs), With<Player>>,
+    camera_query: Query<(&MainCamera, &Transform), Without<Player>>,
+    config: Res<PlayerConfig>,
+    mut shakes: EventWriter<CameraShake>
 ) {
-    for (transform, mut impulse, mut player) in player_query.iter_mut() {
-        if inputs.dash_button && !player.is_dashing {
-            // if player.dashes < 120 {
-                println!("Dashing");
-                player.is_dashing = true;
-                player.dashes = player.dashes + 1;
-                impulse.impulse = transform.back() * DASH_IMPULSE;
-                // player.is_jumping = true;
-                player.last_dash_time = time.elapsed_seconds();
-            // }
-        }
-        if player.last_dash_time != -1. && player.last_dash_time + DASH_TIME < time.elapsed_seconds() {
-            impulse.impulse = Vec3::ZERO;
+    for (entity, transform, mut controller, mut player, inputs) in player_query.iter_mut() {
+        player.dash_cooldown_timer = (player.dash_cooldown_timer - time.delta_seconds()).max(0.);
+        if player.dash_charges < config.dash_charges {
+            player.dash_recharge_timer += time.delta_seconds();
+            if player.dash_recharge_timer >= config.dash_recharge_time {
+                player.dash_recharge_timer = 0.;
+                player.dash_charges += 1;
+            }
+        } else {
+            player.dash_charges = config.dash_charges;
+            player.dash_recharge_timer = 0.;
+        }
+
+        let can_dash = player.dash_charges > 0
+            && player.dash_cooldown_timer <= 0.
+            && (player.is_grounded || player.air_dashes < config.max_air_dashes);
+        if inputs.just_pressed(Action::Dash) && can_dash && !player.is_dashing && !player.is_diving && !player.is_stunned() && !player.is_on_ledge() {
+            // Dash where the stick points relative to the camera, or straight ahead without input
+            let mut direction = Vec3::ZERO;
+            if let Some((_, camera_transform)) = camera_query.iter().find(|(camera, _)| camera.player == entity) {
+                direction = inputs.player_movement.x * camera_transform.right() + inputs.player_movement.y * camera_transform.forward();
+            }
+            direction = (direction * Vec3::new(1., 0., 1.)).normalize_or_zero();
+            if direction == Vec3::ZERO {
+                direction = transform.back();
+            }
+
+            player.is_dashing = true;
+            player.dash_charges -= 1;
+            player.dash_cooldown_timer = config.dash_cooldown;
+            if !player.is_grounded {
+                player.air_dashes += 1;
+            }
+            controller.velocity += direction * config.dash_impulse;
+            player.last_dash_time = time.elapsed_seconds();
+            shakes.send(CameraShake { player: Some(entity), intensity: DASH_SHAKE });
+        }
+        if player.last_dash_time != -1. && player.last_dash_time + config.dash_time < time.elapsed_seconds() {
             player.last_dash_time = -1.;
             player.is_dashing = false;
         }
     }
 }
 
+fn player_dive_system(
+    time: Res<Time>,
+    mut player_query: Query<(&Transform, &mut CharacterController, &mut Player, &Inputs), With<Player>>,
+    config: Res<PlayerConfig>
+) {
+    for (transform, mut controller, mut player, inputs) in player_query.iter_mut() {
+        if inputs.just_pressed(Action::Dive) && !player.is_diving && !player.is_stunned() && !player.is_on_ledge() {
+            let forward = transform.back() * Vec3::new(1., 0., 1.);
+            controller.velocity = forward.normalize_or_zero() * config.dive_speed + Vec3::Y * config.dive_vertical_speed;
+            player.is_diving = true;
+            player.is_dashing = false;
+            player.last_dash_time = -1.;
+            player.dive_timer = config.dive_recovery_time;
+        } else if player.is_diving && player.is_grounded {
+            player.dive_timer -= time.delta_seconds();
+            if player.dive_timer <= 0. {
+                player.is_diving = false;
+            }
+        }
+    }
+}
+
+fn player_ledge_system(
+    time: Res<Time>,
+    rapier_context: Res<RapierContext>,
+    mut player_query: Query<(&mut Player, &mut Transform, &mut CharacterController, &Inputs), With<Player>>,
+    config: Res<PlayerConfig>
+) {
+    for (mut player, mut transform, mut controller, inputs) in player_query.iter_mut() {
+        player.ledge_regrab_timer = (player.ledge_regrab_timer - time.delta_seconds()).max(0.);
+        match player.ledge {
+            LedgeState::None => {
+                let normal = if let Some(normal) = player.wall_normal { normal } else { continue };
+                if player.ledge_regrab_timer > 0. || controller.velocity.y > 0. {
+                    continue;
+                }
+                // Look down onto the top of the wall, from just above the hands' reach
+                let ray_origin = transform.translation
+                    - normal * (WALL_CHECK_RADIUS + config.wall_check_distance + 0.1)
+                    + Vec3::Y * (LEDGE_HANDS_HEIGHT + config.ledge_reach);
+                let hit = rapier_context.cast_ray_and_get_normal(
+                    ray_origin, -Vec3::Y, config.ledge_reach * 2., false, ground_query_filter(),
+                );
+                if let Some((_entity, intersection)) = hit {
+                    if intersection.toi > 0. && intersection.normal.y > 1. - WALL_MAX_NORMAL_Y {
+                        let point = Vec3::new(transform.translation.x, intersection.point.y, transform.translation.z);
+                        player.ledge = LedgeState::Hanging { point, normal };
+                        player.is_jumping = false;
+                        player.is_dashing = false;
+                        player.last_dash_time = -1.;
+                        controller.velocity = Vec3::ZERO;
+                        controller.enabled = false;
+                        let hang_position = point - Vec3::Y * LEDGE_HANDS_HEIGHT;
+                        transform.translation = hang_position;
+                        // Face the wall
+                        transform.look_at(hang_position + normal, Vec3::Y);
+                    }
+                }
+            }
+            LedgeState::Hanging { point, normal } => {
+                if inputs.just_pressed(Action::Jump) {
+                    player.ledge = LedgeState::Mantling {
+                        from: transform.translation,
+                        to: point - normal * (WALL_CHECK_RADIUS + config.wall_check_distance + LEDGE_STEP_IN) + Vec3::Y * PLAYER_STAND_HEIGHT,
+                        elapsed: 0.,
+                    };
+                } else if inputs.just_pressed(Action::Dive) {
+                    player.ledge = LedgeState::None;
+                    player.ledge_regrab_timer = LEDGE_REGRAB_TIME;
+                    controller.enabled = true;
+                }
+            }
+            LedgeState::Mantling { from, to, elapsed } => {
+                let elapsed = elapsed + time.delta_seconds();
+                let t = (elapsed / config.mantle_time).min(1.);
+                // Pull up first, then step forward onto the ledge
+                let up = (t * 2.).min(1.);
+                let forward = t * t;
+                transform.translation = Vec3::new(
+                    from.x + (to.x - from.x) * forward,
+                    from.y + (to.y - from.y) * up * (2. - up),
+                    from.z + (to.z - from.z) * forward,
+                );
+                if t >= 1. {
+                    player.ledge = LedgeState::None;
+                    controller.velocity = Vec3::ZERO;
+                    controller.enabled = true;
+                } else {
+                    player.ledge = LedgeState::Mantling { from, to, elapsed };
+                }
+            }
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
 fn player_movement_system(
-    mut player_query: Query<(&Player, &mut Transform, &mut Velocity), With<Player>>,
-    mut target_query: Query<&mut Transform, (With<PlayerMovementIndicator>, Without<Player>, Without<MainCamera>)>,
-    mut camera_query: Query<&mut Transform, (With<MainCamera>, Without<Player>, Without<PlayerMovementIndicator>)>,
-    inputs: Res<Inputs>
+    time: Res<Time>,
+    mut player_query: Query<(Entity, &Player, Option<&PlayerSlot>, &mut Transform, &mut CharacterController, &Inputs), With<Player>>,
+    mut target_query: Query<(&PlayerMovementIndicator, &mut Transform), (Without<Player>, Without<MainCamera>)>,
+    camera_query: Query<(&MainCamera, &Transform), (Without<Player>, Without<PlayerMovementIndicator>)>,
+    config: Res<PlayerConfig>,
+    spawn_points: Res<SpawnPoints>
 ) {
-        if let Ok(mut target_transform) = target_query.get_single_mut() {
-            for (player, mut player_transform, mut player_vel) in player_query.iter_mut() {
-                if let Ok(camera_transform) = camera_query.get_single_mut() {
-                    let move_right = inputs.player_movement.x * PLAYER_SPEED * camera_transform.right();
-                    let move_forward = inputs.player_movement.y * PLAYER_SPEED * camera_transform.forward();
-                    let mut target_final_pos = player_transform.translation + (move_right / 5. + move_forward / 5.);
-                    let mut look_final_pos = player_transform.translation + (-move_right / 5. + -move_forward / 5.);
-                    look_final_pos.y = player_transform.translation.y;
-                    target_final_pos.y = player_transform.translation.y;
-                    
-                    target_transform.translation = target_final_pos;
-                    if !player.is_dashing && (inputs.player_movement.x != 0. || inputs.player_movement.y != 0.) {
-                        player_vel.linvel = (move_right + move_forward) * Vec3::new(1.,0.,1.) + Vec3::new(0., player_vel.linvel.y,0.);
+        for (entity, player, slot, mut player_transform, mut controller, inputs) in player_query.iter_mut() {
+            if let Some((_, camera_transform)) = camera_query.iter().find(|(camera, _)| camera.player == entity) {
+                // Flatten the camera axes so looking up or down doesn't slow the player down
+                let camera_right = (camera_transform.right() * Vec3::new(1.,0.,1.)).normalize_or_zero();
+                let camera_forward = (camera_transform.forward() * Vec3::new(1.,0.,1.)).normalize_or_zero();
+                let move_right = inputs.player_movement.x * config.speed * camera_right;
+                let move_forward = inputs.player_movement.y * config.speed * camera_forward;
+                let mut target_final_pos = player_transform.translation + (move_right / 5. + move_forward / 5.);
+                let mut look_final_pos = player_transform.translation + (-move_right / 5. + -move_forward / 5.);
+                look_final_pos.y = player_transform.translation.y;
+                target_final_pos.y = player_transform.translation.y;
+
+                for (indicator, mut target_transform) in target_query.iter_mut() {
+                    if indicator.0 == entity {
+                        target_transform.translation = target_final_pos;
+                    }
+                }
+                if player.is_stunned() || player.is_on_ledge() {
+                    // Ragdolling or hanging, something else is in charge
+                } else if player.is_diving {
+                    // Belly-slide: only nudge the current velocity and slow down on the ground
+                    let steering = (move_right + move_forward) * Vec3::new(1.,0.,1.) * config.dive_steering * time.delta_seconds();
+                    let mut horizontal = controller.velocity * Vec3::new(1.,0.,1.) + steering;
+                    if player.is_grounded {
+                        horizontal *= (1. - config.dive_slide_friction * time.delta_seconds()).max(0.);
+                    }
+                    controller.velocity = horizontal + Vec3::new(0., controller.velocity.y,0.);
+                } else if player.grabbed_by.is_some() {
+                    // Held by another player, the stick only leans against the pull
+                    controller.velocity += (move_right + move_forward) * Vec3::new(1.,0.,1.) * time.delta_seconds();
+                } else if !player.is_dashing && player.wall_jump_timer <= 0. {
+                    // No friction on a kinematic body, so stop when there is no input
+                    controller.velocity = (move_right + move_forward) * Vec3::new(1.,0.,1.) + Vec3::new(0., controller.velocity.y,0.);
+                    if inputs.player_movement.x != 0. || inputs.player_movement.y != 0. {
                         player_transform.look_at(look_final_pos, Vec3::Y);
                     }
+                }
 
-                    if player_transform.translation.y <= -20. {
-                        player_transform.translation = Vec3::new(0.,2.,0.);
-                    }
+                if player_transform.translation.y <= -20. {
+                    player_transform.translation = spawn_points.get(slot.map_or(0, |slot| slot.index));
+                    controller.velocity = Vec3::ZERO;
                 }
             }
         }
 }
 
+fn player_animation_system(
+    mut player_query: Query<(&CharacterController, &Player, &mut PlayerAnimation)>,
+) {
+    for (controller, player, mut animation) in player_query.iter_mut() {
+        let new_animation = if let LedgeState::Hanging { .. } = player.ledge {
+            PlayerAnimation::Hang
+        } else if let LedgeState::Mantling { .. } = player.ledge {
+            PlayerAnimation::Mantle
+        } else if player.is_diving {
+            PlayerAnimation::Dive
+        } else if player.is_jumping {
+            PlayerAnimation::Jump
+        } else if controller.velocity.x != 0. || controller.velocity.z != 0. {
+            PlayerAnimation::Run
+        } else {
+            PlayerAnimation::Idle
+        };
+        if *animation != new_animation {
+            *animation = new_animation;
+        }
+    }
+}
+
 fn animation_controller_system(
     animations: Res<Animations>,
-    mut animation_query: Query<&mut AnimationPlayer>,
-    player_query: Query<(&Velocity, &Player), With<Player>>,
+    mut animation_query: Query<(Entity, &mut AnimationPlayer)>,
+    parent_query: Query<&Parent>,
+    model_query: Query<&PlayerAnimation>,
 ) {
-    if let Ok(player) = player_query.get_single() {
-        if let Ok(mut anim_player) = animation_query.get_single_mut() {
-            if player.1.is_jumping {
-                anim_player.play(animations.0[1].clone_weak());
-            } else if player.0.linvel.x != 0. || player.0.linvel.z != 0. {
-                anim_player.play(animations.0[2].clone_weak()).repeat();
-            } else {
-                anim_player.play(animations.0[0].clone_weak()).repeat();
+    for (entity, mut anim_player) in animation_query.iter_mut() {
+        // The animation player sits somewhere in the model's scene, below the entity with the state
+        let mut ancestor = entity;
+        while let Ok(parent) = parent_query.get(ancestor) {
+            ancestor = parent.get();
+            if model_query.contains(ancestor) {
+                break;
+            }
+        }
+        if let Ok(animation) = model_query.get(ancestor) {
+            anim_player.play(animations.0[animation.clip()].clone_weak());
+            if animation.repeat() {
+                anim_player.repeat();
             }
         }
     }
 }
 
 fn check_is_grounded(
-    rapier_context: Res<RapierContext>,
-    mut player_query: Query<(&mut Player, &Transform), With<Player>>
+    time: Res<Time>,
+    mut player_query: Query<(Entity, &mut Player, &CharacterController, &Transform), With<Player>>,
+    config: Res<PlayerConfig>,
+    mut shakes: EventWriter<CameraShake>
 ) {
-    for (mut player, player_transform) in player_query.iter_mut() {
-        let ray_pos = player_transform.translation;
-        let ray_dir = Vec3::new(0., -1., 0.);
-        let max_toi = 1.5;
-        let solid = true;
-        let filter = QueryFilter::default().groups(InteractionGroups::new(Group::GROUP_10, Group::GROUP_1));
-    
-        player.is_grounded = false;
-        rapier_context.intersections_with_ray(
-        ray_pos, ray_dir, max_toi, solid, filter,
-        |_entity, _intersection| {
+    for (entity, mut player, controller, transform) in player_query.iter_mut() {
+        let landed = controller.is_grounded && !player.is_grounded;
+        let fall_height = player.air_peak_height - transform.translation.y;
+        if landed && fall_height > LANDING_SHAKE_HEIGHT {
+            shakes.send(CameraShake { player: Some(entity), intensity: (fall_height - LANDING_SHAKE_HEIGHT) * LANDING_SHAKE });
+        }
+        player.air_peak_height = if controller.is_grounded {
+            transform.translation.y
+        } else {
+            player.air_peak_height.max(transform.translation.y)
+        };
+        player.is_grounded = controller.is_grounded;
+        if player.is_grounded {
+            player.air_dashes = 0;
+        }
+        if landed && config.dash_recharge_on_landing {
+            player.dash_charges = config.dash_charges;
+            player.dash_recharge_timer = 0.;
+        }
+        // Don't reset the jump on the frame it leaves the ground
+        if controller.is_grounded && controller.velocity.y <= 0. {
             player.is_jumping = false;
-            player.is_grounded = true;
             player.jumps_without_ground = 0;
-            true // Return `false` instead if we want to stop searching for other hits.
-        });
+            player.coyote_timer = config.coyote_time;
+        } else {
+            player.coyote_timer = (player.coyote_timer - time.delta_seconds()).max(0.);
+        }
+    }
+}
+
+/// Filter for scene queries that should see the level geometry the player stands on.
+pub fn ground_query_filter<'a>() -> QueryFilter<'a> {
+    let groups = CollisionGroups::new(GROUND_COLLISION.filters, GROUND_COLLISION.memberships);
+    QueryFilter::default().groups(groups.into())
+}
+
+fn check_is_on_wall(
+    time: Res<Time>,
+    rapier_context: Res<RapierContext>,
+    mut player_query: Query<(&mut Player, &Transform, &CharacterController), With<Player>>,
+    config: Res<PlayerConfig>
+) {
+    let filter = ground_query_filter();
+    for (mut player, transform, controller) in player_query.iter_mut() {
+        player.wall_jump_timer = (player.wall_jump_timer - time.delta_seconds()).max(0.);
+        player.wall_normal = None;
+        if player.is_grounded || player.is_diving || player.is_stunned() || player.is_on_ledge() {
+            continue;
+        }
+
+        let direction = (transform.back() * Vec3::new(1., 0., 1.)).normalize_or_zero();
+        let origin = transform.translation + controller.shape_offset;
+        let hit = rapier_context.cast_shape(
+            origin, Quat::IDENTITY, direction, &Collider::ball(WALL_CHECK_RADIUS), config.wall_check_distance, filter,
+        );
+        if let Some((_entity, toi)) = hit {
+            // normal1 is on the cast ball, pointing at the wall
+            let normal = -toi.normal1;
+            if toi.status != TOIStatus::Penetrating && normal.y.abs() < WALL_MAX_NORMAL_Y {
+                player.wall_normal = Some(normal);
+                if config.wall_jump_reset == WallJumpReset::OnWallTouch {
+                    player.jumps_without_ground = 0;
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+}
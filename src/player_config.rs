@@ -0,0 +1,264 @@
+use bevy::{prelude::*, asset::{AssetLoader, LoadContext, LoadedAsset}, reflect::TypeUuid, utils::{BoxedFuture, HashMap}};
+use serde::Deserialize;
+
+use crate::ron_file;
+
+pub struct PlayerConfigPlugin;
+
+const PLAYER_CONFIG: &str = "config/default.player.ron";
+
+/// Movement tuning used by the player systems.
+/// The active values are copied from the selected preset of the
+/// `PlayerConfigAsset` every time the asset is (re)loaded.
+#[derive(Resource, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PlayerConfig {
+    pub speed: f32,
+    pub jump_height: f32,
+    pub dash_impulse: f32,
+    pub dash_time: f32,
+    // Dash charges, and the time to refill one of them
+    pub dash_charges: u8,
+    pub dash_recharge_time: f32,
+    // Refill every charge when touching the ground
+    pub dash_recharge_on_landing: bool,
+    // Minimum time between two dashes
+    pub dash_cooldown: f32,
+    // Dashes allowed before touching the ground again
+    pub max_air_dashes: u8,
+    pub gravity_scale: f32,
+    // How far below the feet the ground is still considered "under" the player
+    pub ground_check_distance: f32,
+    // Extra jumps in the air, on top of the ground or coyote jump
+    pub max_jumps_without_ground: i8,
+    // Grace period to jump after walking off a ledge
+    pub coyote_time: f32,
+    // How long a jump press is remembered before landing
+    pub jump_buffer_time: f32,
+    // Upward velocity is multiplied by this when jump is released early
+    pub jump_cut_multiplier: f32,
+    // Vertical speed under which the jump is considered at its apex
+    pub apex_threshold: f32,
+    pub apex_gravity_multiplier: f32,
+    pub fall_gravity_multiplier: f32,
+    // Dive lunge speed forward and upward
+    pub dive_speed: f32,
+    pub dive_vertical_speed: f32,
+    // How much the stick still steers while diving
+    pub dive_steering: f32,
+    // Horizontal slowdown per second while belly-sliding
+    pub dive_slide_friction: f32,
+    // Time on the ground before getting up from a dive
+    pub dive_recovery_time: f32,
+    // Radius in front of the player where bodies can be grabbed
+    pub grab_reach: f32,
+    // Where the grabbed body is held, in front of the player
+    pub grab_hold_distance: f32,
+    pub grab_stiffness: f32,
+    pub grab_damping: f32,
+    // The grab breaks once the body is dragged this far from the hold point
+    pub grab_break_distance: f32,
+    // Seconds of holding on a full stamina bar, and to refill it
+    pub grab_stamina_time: f32,
+    pub grab_stamina_recovery_time: f32,
+    // Stamina fraction needed to start a new grab
+    pub grab_min_stamina: f32,
+    // A grabbed player breaks free once mashing jump or dash fills this to 1,
+    // each press adds `grab_struggle_per_press` and it drains per second
+    pub grab_struggle_per_press: f32,
+    pub grab_struggle_decay: f32,
+    // Default time knocked over by a `Knockback`
+    pub stun_time: f32,
+    // Spin applied per unit of knockback impulse
+    pub knockback_spin: f32,
+    // Distance ahead of the player to look for a wall
+    pub wall_check_distance: f32,
+    // Max falling speed while sliding on a wall
+    pub wall_slide_speed: f32,
+    pub wall_jump_height: f32,
+    // Horizontal speed away from the wall
+    pub wall_jump_push: f32,
+    // Time the stick can't steer back towards the wall
+    pub wall_jump_lock_time: f32,
+    pub wall_jump_reset: WallJumpReset,
+    // How far above the hands a ledge can still be grabbed
+    pub ledge_reach: f32,
+    // Time to climb onto a ledge
+    pub mantle_time: f32,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        Self {
+            speed: 10.,
+            jump_height: 7.5,
+            dash_impulse: 5.5,
+            dash_time: 0.1,
+            dash_charges: 2,
+            dash_recharge_time: 1.5,
+            dash_recharge_on_landing: true,
+            dash_cooldown: 0.3,
+            max_air_dashes: 1,
+            gravity_scale: 2.,
+            ground_check_distance: 0.3,
+            max_jumps_without_ground: 0,
+            coyote_time: 0.12,
+            jump_buffer_time: 0.12,
+            jump_cut_multiplier: 0.5,
+            apex_threshold: 1.5,
+            apex_gravity_multiplier: 0.5,
+            fall_gravity_multiplier: 1.6,
+            dive_speed: 12.,
+            dive_vertical_speed: 3.,
+            dive_steering: 4.,
+            dive_slide_friction: 3.,
+            dive_recovery_time: 0.6,
+            grab_reach: 0.8,
+            grab_hold_distance: 1.2,
+            grab_stiffness: 40.,
+            grab_damping: 4.,
+            grab_break_distance: 2.5,
+            grab_stamina_time: 3.,
+            grab_stamina_recovery_time: 4.,
+            grab_min_stamina: 0.25,
+            grab_struggle_per_press: 0.2,
+            grab_struggle_decay: 0.5,
+            stun_time: 1.5,
+            knockback_spin: 0.5,
+            wall_check_distance: 0.3,
+            wall_slide_speed: 2.5,
+            wall_jump_height: 8.,
+            wall_jump_push: 7.,
+            wall_jump_lock_time: 0.2,
+            wall_jump_reset: WallJumpReset::OnWallJump,
+            ledge_reach: 0.5,
+            mantle_time: 0.4,
+        }
+    }
+}
+
+/// When wall jumps give back the jumps used in the air.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum WallJumpReset {
+    // Wall jumps count as air jumps
+    Never,
+    // Touching a wall gives every air jump back
+    OnWallTouch,
+    // Jumping off a wall gives every air jump back
+    OnWallJump,
+}
+
+/// RON file with every named preset, e.g. "floaty" or "tight".
+#[derive(Deserialize, TypeUuid)]
+#[uuid = "6b0f1f8e-3d8a-4c1e-9a59-2f4be0d7c5a1"]
+pub struct PlayerConfigAsset {
+    pub default_preset: String,
+    pub presets: HashMap<String, PlayerConfig>,
+}
+
+impl PlayerConfigAsset {
+    /// Preset names in a stable order, for cycling through them.
+    pub fn preset_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.presets.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+/// Handle to the loaded presets and the name of the one in use.
+/// Setting `preset` to `None` falls back to the file's `default_preset`.
+#[derive(Resource)]
+pub struct PlayerConfigPreset {
+    pub handle: Handle<PlayerConfigAsset>,
+    pub preset: Option<String>,
+}
+
+#[derive(Default)]
+struct PlayerConfigLoader;
+
+impl AssetLoader for PlayerConfigLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let config: PlayerConfigAsset = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(config));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["player.ron"]
+    }
+}
+
+impl Plugin for PlayerConfigPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .add_asset::<PlayerConfigAsset>()
+        .init_asset_loader::<PlayerConfigLoader>()
+        .insert_resource(PlayerConfig::default())
+        .add_startup_system(load_player_config)
+        .add_system(apply_player_config);
+    }
+}
+
+fn load_player_config(
+    mut commands: Commands,
+    ass: Res<AssetServer>
+) {
+    // Also read it right away, so the first frames don't run on the built-in defaults
+    // and replays start from the same config they were recorded with
+    if let Some(asset) = ron_file::load_asset::<PlayerConfigAsset>(PLAYER_CONFIG) {
+        if let Some(config) = asset.presets.get(&asset.default_preset) {
+            commands.insert_resource(config.clone());
+        }
+    }
+    commands.insert_resource(PlayerConfigPreset {
+        handle: ass.load(PLAYER_CONFIG),
+        preset: None,
+    });
+}
+
+fn apply_player_config(
+    mut events: EventReader<AssetEvent<PlayerConfigAsset>>,
+    assets: Res<Assets<PlayerConfigAsset>>,
+    preset: Res<PlayerConfigPreset>,
+    mut config: ResMut<PlayerConfig>,
+) {
+    let mut reload = preset.is_changed();
+    for ev in events.iter() {
+        match ev {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                reload |= *handle == preset.handle;
+            }
+            AssetEvent::Removed { .. } => {}
+        }
+    }
+    if !reload {
+        return;
+    }
+
+    if let Some(asset) = assets.get(&preset.handle) {
+        let name = preset.preset.as_ref().unwrap_or(&asset.default_preset);
+        if let Some(new_config) = asset.presets.get(name) {
+            info!("Using player config preset: {}", name);
+            *config = new_config.clone();
+        } else {
+            warn!("Unknown player config preset: {}", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_config_parses_and_has_its_default_preset() {
+        let asset: PlayerConfigAsset = ron::from_str(include_str!("../assets/config/default.player.ron")).unwrap();
+        assert!(asset.presets.contains_key(&asset.default_preset));
+    }
+}
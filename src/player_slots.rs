@@ -0,0 +1,193 @@
+use bevy::{prelude::*, input::InputSystem, utils::HashMap};
+
+use crate::{gamepad::Inputs, grab::{GrabReleaseReason, GrabReleased}, input_devices::{DeviceInputs, InputDevice, InputLabel}, input_map::Action, level::SpawnPoints, player::{spawn_player, Player, PlayerMovementIndicator}, player_config::PlayerConfig, spectator::InputSuspended};
+
+pub struct PlayerSlotsPlugin;
+
+pub const MAX_PLAYERS: usize = 4;
+// Player 1's device must be left alone this long before another device can take it over
+const FIRST_SLOT_SWITCH_IDLE_TIME: f32 = 2.;
+
+/// Which local player this is, and the device controlling it.
+/// Slot 0 plays with every device that has no slot of its own, `device` is the one used last.
+/// Once someone plays slot 0, other gamepads join as new players by pressing pause.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct PlayerSlot {
+    pub index: usize,
+    pub device: InputDevice,
+}
+
+pub struct PlayerJoined {
+    pub player: Entity,
+    pub slot: PlayerSlot,
+}
+
+/// Sent after the player entity was despawned.
+pub struct PlayerLeft {
+    pub player: Entity,
+    pub slot: PlayerSlot,
+}
+
+impl Plugin for PlayerSlotsPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .add_event::<PlayerJoined>()
+        .add_event::<PlayerLeft>()
+        .add_startup_system(spawn_first_player)
+        .add_system_to_stage(CoreStage::PreUpdate, player_join_system.after(InputLabel::Aggregate))
+        .add_system_to_stage(CoreStage::PreUpdate, player_leave_system.after(InputSystem))
+        .add_system_to_stage(CoreStage::PreUpdate, player_inputs_system.after(player_join_system));
+    }
+}
+
+fn spawn_first_player(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    ass: Res<AssetServer>,
+    config: Res<PlayerConfig>,
+    spawn_points: Res<SpawnPoints>,
+    mut joined: EventWriter<PlayerJoined>,
+) {
+    let slot = PlayerSlot { index: 0, device: InputDevice::KeyboardMouse };
+    let player = spawn_player(&mut commands, &mut meshes, &mut materials, &ass, &config, spawn_points.get(0));
+    join(&mut commands, player, slot, &mut joined);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn player_join_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    ass: Res<AssetServer>,
+    config: Res<PlayerConfig>,
+    spawn_points: Res<SpawnPoints>,
+    device_inputs: Res<DeviceInputs>,
+    mut slot_query: Query<&mut PlayerSlot>,
+    mut joined: EventWriter<PlayerJoined>,
+    // Until a device is used, the first one to be used takes slot 0 instead of joining
+    mut first_slot_used: Local<bool>,
+    // Seconds since slot 0's device was last used
+    mut first_device_idle_time: Local<f32>,
+) {
+    // Players spawned this frame aren't in the query yet
+    let mut taken: Vec<usize> = slot_query.iter().map(|slot| slot.index).collect();
+    let mut owned: Vec<InputDevice> = slot_query.iter().filter(|slot| slot.index != 0).map(|slot| slot.device).collect();
+    let mut first_slot = if let Some(slot) = slot_query.iter_mut().find(|slot| slot.index == 0) { slot } else { return };
+    if device_inputs.0.get(&first_slot.device).is_some_and(|inputs| inputs.is_active()) {
+        *first_device_idle_time = 0.;
+    } else {
+        *first_device_idle_time += time.delta_seconds();
+    }
+
+    for (device, inputs) in device_inputs.sorted() {
+        if owned.contains(device) || !inputs.is_active() {
+            continue;
+        }
+        // Checked before anything can switch player 1's device
+        let someone_else_on_first_slot = *first_slot_used && first_slot.device != *device;
+        if someone_else_on_first_slot && inputs.pressed(Action::Pause) && matches!(device, InputDevice::Gamepad(_)) {
+            let index = if let Some(index) = (0..MAX_PLAYERS).find(|index| !taken.contains(index)) { index } else { continue };
+            taken.push(index);
+            owned.push(*device);
+
+            let slot = PlayerSlot { index, device: *device };
+            let player = spawn_player(&mut commands, &mut meshes, &mut materials, &ass, &config, spawn_points.get(index));
+            join(&mut commands, player, slot, &mut joined);
+            continue;
+        }
+
+        if first_slot.device != *device {
+            // Player 1 keeps its device while still using it
+            if *first_slot_used && *first_device_idle_time < FIRST_SLOT_SWITCH_IDLE_TIME {
+                continue;
+            }
+            info!("Player 1 switched to {:?}", device);
+            first_slot.device = *device;
+            *first_device_idle_time = 0.;
+        }
+        *first_slot_used = true;
+    }
+}
+
+fn join(
+    commands: &mut Commands,
+    player: Entity,
+    slot: PlayerSlot,
+    joined: &mut EventWriter<PlayerJoined>,
+) {
+    commands.entity(player).insert(slot);
+    joined.send(PlayerJoined { player, slot });
+}
+
+fn player_leave_system(
+    mut commands: Commands,
+    mut gamepad_evr: EventReader<GamepadEvent>,
+    mut player_query: Query<(Entity, &mut PlayerSlot, &Player)>,
+    indicator_query: Query<(Entity, &PlayerMovementIndicator)>,
+    mut left: EventWriter<PlayerLeft>,
+    mut released: EventWriter<GrabReleased>,
+) {
+    for ev in gamepad_evr.iter() {
+        if !matches!(ev.event_type, GamepadEventType::Disconnected) {
+            continue;
+        }
+        for (entity, mut slot, player) in player_query.iter_mut() {
+            if slot.device != InputDevice::Gamepad(ev.gamepad) {
+                continue;
+            }
+            // The first player stays, on whatever device is used next
+            if slot.index == 0 {
+                slot.device = InputDevice::KeyboardMouse;
+                continue;
+            }
+            // Let go of whatever it was holding so it doesn't keep being pulled
+            if let Some(target) = player.grabbing {
+                released.send(GrabReleased { grabber: entity, target, reason: GrabReleaseReason::LetGo });
+            }
+            for (indicator_entity, indicator) in indicator_query.iter() {
+                if indicator.0 == entity {
+                    commands.entity(indicator_entity).despawn_recursive();
+                }
+            }
+            commands.entity(entity).despawn_recursive();
+            left.send(PlayerLeft { player: entity, slot: *slot });
+        }
+    }
+}
+
+/// Gives every player the inputs of its own device, with its own button edges.
+/// The first player gets every device without a player of its own, merged.
+/// `unsuspended_inputs` keeps what each player's devices reported even while its inputs are suspended,
+/// so a button held through the suspension doesn't count as a new press once it ends.
+fn player_inputs_system(
+    device_inputs: Res<DeviceInputs>,
+    inputs: Res<Inputs>,
+    mut player_query: Query<(Entity, &PlayerSlot, &mut Inputs, Option<&InputSuspended>)>,
+    mut unsuspended_inputs: Local<HashMap<Entity, Inputs>>,
+) {
+    unsuspended_inputs.retain(|entity, _| player_query.contains(*entity));
+    let owned: Vec<InputDevice> = player_query.iter().filter(|(_, slot, ..)| slot.index != 0).map(|(_, slot, ..)| slot.device).collect();
+    for (entity, slot, mut player_inputs, suspended) in player_query.iter_mut() {
+        let mut new_inputs = if slot.index == 0 {
+            let mut merged = Inputs::default();
+            for (_, device_input) in device_inputs.sorted().into_iter().filter(|(device, _)| !owned.contains(device)) {
+                merged.merge(device_input);
+            }
+            merged
+        } else {
+            device_inputs.0.get(&slot.device).cloned().unwrap_or_else(Inputs::default)
+        };
+        new_inputs.update_edges(unsuspended_inputs.get(&entity).unwrap_or(&player_inputs));
+        new_inputs.frame = inputs.frame;
+        unsuspended_inputs.insert(entity, new_inputs.clone());
+        *player_inputs = if suspended.is_some() {
+            let mut idle = Inputs::default();
+            idle.frame = inputs.frame;
+            idle
+        } else {
+            new_inputs
+        };
+    }
+}
@@ -0,0 +1,373 @@
+use std::{path::Path, time::Duration};
+
+use bevy::{prelude::*, app::AppExit, time::TimeUpdateStrategy};
+use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{gamepad::Inputs, input_devices::{DeviceInputs, InputDevice, InputLabel}, input_map::Action, player::Player, player_slots::PlayerSlot, ron_file};
+
+pub struct ReplayPlugin;
+
+/// Time per frame in fixed timestep mode, which recording and replaying always use.
+pub const FIXED_TIMESTEP: f32 = 1. / 60.;
+
+// How far a replayed player may end up from the recorded position
+const REPLAY_TOLERANCE: f32 = 0.01;
+
+/// Name of the level being played, `levels/<name>.level.ron`, saved with recordings.
+#[derive(Resource)]
+pub struct CurrentLevel(pub String);
+
+/// When present, every frame advances `Time` and the physics by exactly this many seconds.
+#[derive(Resource)]
+pub struct FixedTimestep(pub f32);
+
+/// A run that can be replayed frame by frame.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Recording {
+    pub level: String,
+    pub timestep: f32,
+    // Inputs of every device, run-length encoded as (frame count, inputs)
+    pub frames: Vec<(u32, Vec<RecordedInputs>)>,
+    // Where each player slot ended up, to check replays against
+    pub final_positions: Vec<(usize, Vec3)>,
+}
+
+/// What a device reported in a frame, buttons as bits in `Action::BUTTONS` order.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedInputs {
+    pub device: InputDevice,
+    pub player_movement: Vec2,
+    pub camera_movement: Vec2,
+    #[serde(default)]
+    pub camera_zoom: f32,
+    pub buttons: u8,
+}
+
+#[derive(Resource)]
+pub enum Replay {
+    Off,
+    // Saved to `path` when the game exits
+    Recording { path: String, recording: Recording },
+    // `run` indexes `recording.frames`, `run_frame` counts frames into that run.
+    // With `rerecord`, the final positions are saved there again instead of being checked
+    Playing { recording: Recording, run: usize, run_frame: u32, rerecord: Option<String> },
+    // Whether the players ended up where they were recorded
+    Finished { matches: bool },
+}
+
+/// Quits once the replay is over, with a failing exit code if it diverged.
+#[derive(Resource)]
+pub struct ExitAfterReplay;
+
+impl Recording {
+    pub fn new(level: String, timestep: f32) -> Self {
+        Self {
+            level,
+            timestep,
+            frames: Vec::new(),
+            final_positions: Vec::new(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Option<Self> {
+        ron_file::load(path)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> bool {
+        ron_file::save(path, self, false)
+    }
+
+    /// Adds a frame of what every device reported.
+    pub fn push_devices(&mut self, device_inputs: &DeviceInputs) {
+        let frame = device_inputs.sorted().into_iter().map(|(device, inputs)| RecordedInputs::new(*device, inputs)).collect();
+        self.push_frame(frame);
+    }
+
+    fn push_frame(&mut self, inputs: Vec<RecordedInputs>) {
+        match self.frames.last_mut() {
+            Some((count, last)) if *last == inputs => *count += 1,
+            _ => self.frames.push((1, inputs)),
+        }
+    }
+}
+
+impl RecordedInputs {
+    fn new(device: InputDevice, inputs: &Inputs) -> Self {
+        let mut buttons = 0;
+        for (i, action) in Action::BUTTONS.iter().enumerate() {
+            if inputs.pressed(*action) {
+                buttons |= 1 << i;
+            }
+        }
+        Self {
+            device,
+            player_movement: inputs.player_movement,
+            camera_movement: inputs.camera_movement,
+            camera_zoom: inputs.camera_zoom,
+            buttons,
+        }
+    }
+
+    fn inputs(&self) -> Inputs {
+        let mut inputs = Inputs::default();
+        inputs.player_movement = self.player_movement;
+        inputs.camera_movement = self.camera_movement;
+        inputs.camera_zoom = self.camera_zoom;
+        for (i, action) in Action::BUTTONS.iter().enumerate() {
+            inputs.set_pressed(*action, self.buttons & (1 << i) != 0);
+        }
+        inputs
+    }
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        // --record <file>, --replay <file>, --fixed-timestep and --level <name>.
+        // Replaying with --record saves the new final positions, e.g. after a gameplay change
+        let mut record_path = None;
+        let mut replay_path = None;
+        let mut fixed_timestep = false;
+        let mut level = "default".to_string();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--record" => record_path = args.next(),
+                "--replay" => replay_path = args.next(),
+                "--fixed-timestep" => fixed_timestep = true,
+                "--level" => level = args.next().unwrap_or(level),
+                _ => {}
+            }
+        }
+
+        app
+        .insert_resource(CurrentLevel(level))
+        .insert_resource(Replay::Off)
+        .add_startup_system(setup_replay)
+        .add_system_to_stage(CoreStage::PreUpdate, replay_inputs_system.after(InputLabel::Read).before(InputLabel::Aggregate))
+        .add_system_to_stage(CoreStage::Last, fixed_timestep_system)
+        .add_system_to_stage(CoreStage::Last, save_recording_system)
+        .add_system_to_stage(CoreStage::Last, exit_after_replay_system);
+
+        if let Some(path) = replay_path {
+            match Recording::load(&path) {
+                // Without a timestep the clock would never advance
+                Some(recording) if recording.timestep > 0. => {
+                    play_recording(app, recording, record_path);
+                    app.insert_resource(ExitAfterReplay);
+                }
+                Some(recording) => {
+                    error!("The replay {} has a timestep of {}, it can't be played back", path, recording.timestep);
+                    std::process::exit(1);
+                }
+                None => {
+                    error!("Could not load the replay {}", path);
+                    std::process::exit(1);
+                }
+            }
+        } else if let Some(path) = record_path {
+            use_fixed_timestep(app, FIXED_TIMESTEP);
+            app.insert_resource(Replay::Recording { path, recording: Recording::new(String::new(), FIXED_TIMESTEP) });
+        } else if fixed_timestep {
+            use_fixed_timestep(app, FIXED_TIMESTEP);
+        }
+    }
+}
+
+/// Replays `recording` in its own level, saving it again to `rerecord` when given.
+pub fn play_recording(app: &mut App, recording: Recording, rerecord: Option<String>) {
+    use_fixed_timestep(app, recording.timestep);
+    app
+    .insert_resource(CurrentLevel(recording.level.clone()))
+    .insert_resource(Replay::Playing { recording, run: 0, run_frame: 0, rerecord });
+}
+
+fn use_fixed_timestep(app: &mut App, timestep: f32) {
+    // `fixed_timestep_system` only takes over at the end of the first frame,
+    // which has already read the clock by the time the startup systems run
+    let startup = app.world.resource::<Time>().startup();
+    app
+    .insert_resource(FixedTimestep(timestep))
+    .insert_resource(TimeUpdateStrategy::ManualInstant(startup + Duration::from_secs_f32(timestep)));
+}
+
+fn setup_replay(
+    fixed: Option<Res<FixedTimestep>>,
+    level: Res<CurrentLevel>,
+    mut replay: ResMut<Replay>,
+    mut rapier_config: ResMut<RapierConfiguration>,
+) {
+    if let Some(fixed) = fixed {
+        rapier_config.timestep_mode = TimestepMode::Fixed { dt: fixed.0, substeps: 1 };
+    }
+    match replay.as_mut() {
+        Replay::Recording { recording, .. } => recording.level = level.0.clone(),
+        Replay::Off | Replay::Playing { .. } | Replay::Finished { .. } => {}
+    }
+}
+
+/// Sets the time of the next frame to exactly one step after this one.
+fn fixed_timestep_system(
+    fixed: Option<Res<FixedTimestep>>,
+    time: Res<Time>,
+    mut update_strategy: ResMut<TimeUpdateStrategy>,
+) {
+    if let Some(fixed) = fixed {
+        let last_update = time.last_update().unwrap_or_else(|| time.startup());
+        *update_strategy = TimeUpdateStrategy::ManualInstant(last_update + Duration::from_secs_f32(fixed.0));
+    }
+}
+
+/// Records what the devices reported, or replaces it with the recorded frame.
+fn replay_inputs_system(
+    mut replay: ResMut<Replay>,
+    mut device_inputs: ResMut<DeviceInputs>,
+    player_query: Query<(&PlayerSlot, &Transform), With<Player>>,
+) {
+    match replay.as_mut() {
+        Replay::Off | Replay::Finished { .. } => {}
+        Replay::Recording { recording, .. } => recording.push_devices(&device_inputs),
+        Replay::Playing { recording, run, run_frame, rerecord } => {
+            if let Some((count, frame)) = recording.frames.get(*run) {
+                device_inputs.0 = frame.iter().map(|recorded| (recorded.device, recorded.inputs())).collect();
+                *run_frame += 1;
+                if *run_frame >= *count {
+                    *run += 1;
+                    *run_frame = 0;
+                }
+                return;
+            }
+
+            // Out of frames
+            if let Some(path) = rerecord {
+                recording.final_positions = player_query.iter().map(|(slot, transform)| (slot.index, transform.translation)).collect();
+                let saved = recording.save(&path);
+                if saved {
+                    info!("Saved the replayed recording to {}", path);
+                }
+                *replay = Replay::Finished { matches: saved };
+                return;
+            }
+
+            // Compare with where the players were when recording stopped
+            let mut matches = true;
+            for (index, expected) in recording.final_positions.iter() {
+                let position = player_query.iter().find(|(slot, _)| slot.index == *index).map(|(_, transform)| transform.translation);
+                if position.is_some_and(|position| position.distance(*expected) <= REPLAY_TOLERANCE) {
+                    info!("Replay: player {} at {:?}, recorded at {:?}", index + 1, position, expected);
+                } else {
+                    matches = false;
+                    error!("Replay: player {} at {:?}, recorded at {:?}", index + 1, position, expected);
+                }
+            }
+            if matches {
+                info!("Replay finished, it matches the recording");
+            } else {
+                error!("Replay finished, it diverged from the recording");
+            }
+            *replay = Replay::Finished { matches };
+        }
+    }
+}
+
+fn save_recording_system(
+    mut exit: EventReader<AppExit>,
+    mut replay: ResMut<Replay>,
+    player_query: Query<(&PlayerSlot, &Transform), With<Player>>,
+) {
+    if exit.iter().count() == 0 {
+        return;
+    }
+    if let Replay::Recording { path, recording } = replay.as_mut() {
+        recording.final_positions = player_query.iter().map(|(slot, transform)| (slot.index, transform.translation)).collect();
+        if recording.save(&path) {
+            info!("Saved the recording to {}", path);
+        }
+    }
+}
+
+fn exit_after_replay_system(
+    exit_after_replay: Option<Res<ExitAfterReplay>>,
+    replay: Res<Replay>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if exit_after_replay.is_none() {
+        return;
+    }
+    match *replay {
+        Replay::Finished { matches: true } => exit.send(AppExit),
+        // `AppExit` can't carry an exit code
+        Replay::Finished { matches: false } => std::process::exit(1),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn recorded(buttons: u8) -> Vec<RecordedInputs> {
+        vec![RecordedInputs {
+            device: InputDevice::KeyboardMouse,
+            player_movement: Vec2::Y,
+            camera_movement: Vec2::ZERO,
+            camera_zoom: 0.,
+            buttons,
+        }]
+    }
+
+    #[test]
+    fn push_frame_run_length_encodes() {
+        let mut recording = Recording::new(String::new(), FIXED_TIMESTEP);
+        let pushed = [recorded(0), recorded(0), recorded(1), recorded(0), recorded(0), recorded(0)];
+        for frame in pushed.iter() {
+            recording.push_frame(frame.clone());
+        }
+        assert_eq!(recording.frames.len(), 3);
+
+        let expanded: Vec<Vec<RecordedInputs>> = recording.frames.iter()
+            .flat_map(|(count, frame)| std::iter::repeat_n(frame.clone(), *count as usize))
+            .collect();
+        assert!(expanded == pushed);
+    }
+
+    #[test]
+    fn recorded_inputs_keep_the_buttons() {
+        let mut inputs = Inputs::default();
+        inputs.player_movement = Vec2::new(0.5, -1.);
+        inputs.set_pressed(Action::Jump, true);
+        inputs.set_pressed(Action::Grab, true);
+        let replayed = RecordedInputs::new(InputDevice::KeyboardMouse, &inputs).inputs();
+        assert_eq!(replayed.player_movement, inputs.player_movement);
+        for action in Action::BUTTONS {
+            assert_eq!(replayed.pressed(action), inputs.pressed(action));
+        }
+    }
+
+    /// Replays every recording in `replays/` without a window, like `--headless --replay <file>`.
+    /// After a change that is meant to move the players, save them again with
+    /// `--headless --replay <file> --record <file>`.
+    #[test]
+    fn committed_replays_match() {
+        let mut replayed = 0;
+        for entry in fs::read_dir("replays").unwrap() {
+            let path = entry.unwrap().path();
+            let recording = Recording::load(&path).unwrap();
+            let frames: u32 = recording.frames.iter().map(|(count, _)| count).sum();
+
+            let mut app = crate::game_app(true);
+            play_recording(&mut app, recording, None);
+            for _ in 0..frames + 2 {
+                app.update();
+            }
+            assert!(
+                matches!(app.world.resource::<Replay>(), Replay::Finished { matches: true }),
+                "{} diverged from its recording", path.display(),
+            );
+            replayed += 1;
+        }
+        assert!(replayed > 0);
+    }
+}
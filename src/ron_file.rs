@@ -0,0 +1,62 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+use bevy::{prelude::*, asset::FileAssetIo};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Reads a RON file saved by the game, `None` when it doesn't exist.
+/// Files that can't be read or parsed are logged and also give `None`.
+pub fn load<T: DeserializeOwned>(path: impl AsRef<Path>) -> Option<T> {
+    let path = path.as_ref();
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) => {
+            error!("Could not read {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    match ron::from_str(&text) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            error!("Could not parse {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Reads a RON file from the assets folder right away, for assets that have to be there before the first frame.
+pub fn load_asset<T: DeserializeOwned>(path: &str) -> Option<T> {
+    let full_path = FileAssetIo::get_base_path().join("assets").join(path);
+    if !full_path.exists() {
+        error!("Could not find asset {}", full_path.display());
+    }
+    load(full_path)
+}
+
+/// Writes `value` to `path`, creating its folder. `pretty` is for files meant to be edited by hand.
+/// Returns whether it was written, failures are logged.
+pub fn save<T: Serialize>(path: impl AsRef<Path>, value: &T, pretty: bool) -> bool {
+    let path = path.as_ref();
+    let text = if pretty {
+        ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default())
+    } else {
+        ron::to_string(value)
+    };
+    let text = match text {
+        Ok(text) => text,
+        Err(err) => {
+            error!("Could not serialize {}: {}", path.display(), err);
+            return false;
+        }
+    };
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    match fs::write(path, text) {
+        Ok(()) => true,
+        Err(err) => {
+            error!("Could not write {}: {}", path.display(), err);
+            false
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::path::Path;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::ron_file;
+
+pub struct SettingsPlugin;
+
+const SETTINGS_PATH: &str = "settings/settings.ron";
+
+/// Player settings, saved to `SETTINGS_PATH` whenever they change.
+#[derive(Resource, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub input: InputSettings,
+    pub accessibility: AccessibilitySettings,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputSettings {
+    // Scales the mouse delta in pixels
+    pub mouse_sensitivity: f32,
+    pub mouse_invert_y: bool,
+    // Scales the scroll wheel lines of a frame into a zoom input
+    pub mouse_wheel_sensitivity: f32,
+    pub gamepad_move_stick: StickSettings,
+    pub gamepad_camera_stick: StickSettings,
+    pub gamepad_camera_sensitivity: f32,
+    pub gamepad_invert_y: bool,
+}
+
+impl Default for InputSettings {
+    fn default() -> Self {
+        Self {
+            mouse_sensitivity: 1.,
+            mouse_invert_y: false,
+            mouse_wheel_sensitivity: 20.,
+            gamepad_move_stick: StickSettings {
+                deadzone: Deadzone::Radial(0.15),
+                curve: ResponseCurve::Linear,
+            },
+            gamepad_camera_stick: StickSettings {
+                deadzone: Deadzone::Radial(0.15),
+                curve: ResponseCurve::Quadratic,
+            },
+            gamepad_camera_sensitivity: 1.,
+            gamepad_invert_y: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessibilitySettings {
+    // Scales every camera shake, 0 turns it off
+    pub camera_shake: f32,
+}
+
+impl Default for AccessibilitySettings {
+    fn default() -> Self {
+        Self { camera_shake: 1. }
+    }
+}
+
+/// How a stick is cleaned up before it reaches the game.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StickSettings {
+    pub deadzone: Deadzone,
+    pub curve: ResponseCurve,
+}
+
+/// Values inside the deadzone read as 0, the rest is rescaled to start from 0.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Deadzone {
+    // On the stick's distance from the center, keeps diagonals smooth
+    Radial(f32),
+    // On each axis separately, makes it easier to move in a straight line
+    Axial(f32),
+}
+
+/// Maps a stick distance from 0 to 1 to the value the game sees.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ResponseCurve {
+    Linear,
+    // More precision near the center
+    Quadratic,
+    // (input, output) points between 0 and 1, linearly interpolated
+    Custom(Vec<(f32, f32)>),
+}
+
+impl StickSettings {
+    pub fn apply(&self, raw: Vec2) -> Vec2 {
+        match self.deadzone {
+            Deadzone::Radial(size) => {
+                let length = raw.length();
+                if length <= size {
+                    return Vec2::ZERO;
+                }
+                raw / length * self.curve.apply(rescale(length, size))
+            }
+            Deadzone::Axial(size) => {
+                let axis = |value: f32| {
+                    if value.abs() <= size { 0. } else { value.signum() * self.curve.apply(rescale(value.abs(), size)) }
+                };
+                Vec2::new(axis(raw.x), axis(raw.y))
+            }
+        }
+    }
+}
+
+fn rescale(value: f32, deadzone: f32) -> f32 {
+    ((value - deadzone) / (1. - deadzone).max(f32::EPSILON)).min(1.)
+}
+
+impl ResponseCurve {
+    pub fn apply(&self, value: f32) -> f32 {
+        match self {
+            ResponseCurve::Linear => value,
+            ResponseCurve::Quadratic => value * value,
+            ResponseCurve::Custom(points) => {
+                let mut previous = (0., 0.);
+                for &(x, y) in points.iter().chain([(1., 1.)].iter()) {
+                    if value <= x {
+                        let t = if x > previous.0 { (value - previous.0) / (x - previous.0) } else { 1. };
+                        return previous.1 + (y - previous.1) * t;
+                    }
+                    previous = (x, y);
+                }
+                previous.1
+            }
+        }
+    }
+}
+
+impl Settings {
+    pub fn load() -> Option<Self> {
+        ron_file::load(SETTINGS_PATH)
+    }
+
+    pub fn save(&self) {
+        ron_file::save(SETTINGS_PATH, self, true);
+    }
+}
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        // Write the defaults on the first run so there is a file to edit,
+        // a file that doesn't parse is left alone for the player to fix
+        let settings = if Path::new(SETTINGS_PATH).exists() {
+            Settings::load().unwrap_or_default()
+        } else {
+            let settings = Settings::default();
+            settings.save();
+            settings
+        };
+        app
+        .insert_resource(settings)
+        .add_system(save_settings);
+    }
+}
+
+fn save_settings(settings: Res<Settings>) {
+    if settings.is_changed() && !settings.is_added() {
+        settings.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn radial_deadzone_rescales_from_its_edge() {
+        let stick = StickSettings { deadzone: Deadzone::Radial(0.2), curve: ResponseCurve::Linear };
+        assert_eq!(stick.apply(Vec2::new(0.1, 0.1)), Vec2::ZERO);
+        assert!(close(stick.apply(Vec2::new(0.6, 0.)).x, 0.5));
+        assert!(close(stick.apply(Vec2::new(0., -1.)).y, -1.));
+        // Keeps the direction of diagonals
+        let diagonal = stick.apply(Vec2::new(0.5, 0.5));
+        assert!(close(diagonal.x, diagonal.y));
+    }
+
+    #[test]
+    fn axial_deadzone_works_per_axis() {
+        let stick = StickSettings { deadzone: Deadzone::Axial(0.2), curve: ResponseCurve::Linear };
+        let value = stick.apply(Vec2::new(0.15, -0.6));
+        assert_eq!(value.x, 0.);
+        assert!(close(value.y, -0.5));
+    }
+
+    #[test]
+    fn response_curves() {
+        assert!(close(ResponseCurve::Linear.apply(0.3), 0.3));
+        assert!(close(ResponseCurve::Quadratic.apply(0.5), 0.25));
+        let custom = ResponseCurve::Custom(vec![(0.5, 0.2)]);
+        assert!(close(custom.apply(0.25), 0.1));
+        assert!(close(custom.apply(0.75), 0.6));
+        assert!(close(custom.apply(1.), 1.));
+    }
+
+    #[test]
+    fn settings_round_trip_and_fill_in_missing_fields() {
+        let text = ron::ser::to_string_pretty(&Settings::default(), ron::ser::PrettyConfig::default()).unwrap();
+        let settings: Settings = ron::from_str(&text).unwrap();
+        assert_eq!(settings.input.mouse_sensitivity, InputSettings::default().mouse_sensitivity);
+
+        let settings: Settings = ron::from_str("(input: (mouse_invert_y: true))").unwrap();
+        assert!(settings.input.mouse_invert_y);
+        assert_eq!(settings.input.mouse_wheel_sensitivity, InputSettings::default().mouse_wheel_sensitivity);
+    }
+}
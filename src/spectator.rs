@@ -0,0 +1,147 @@
+use bevy::prelude::*;
+
+use crate::{camera::{CameraConfig, MainCamera}, camera_rail::CameraRailPlayback, character_controller::CharacterControllerLabel, gamepad::Inputs, input_devices::DeviceInputs, input_map::Action, player_slots::PlayerSlot};
+
+pub struct SpectatorPlugin;
+
+// Units per second, times `FAST_MULTIPLIER` while dash is held
+const FLY_SPEED: f32 = 12.;
+const FAST_MULTIPLIER: f32 = 3.;
+
+/// Something a spectator can cycle to and follow, players and bots.
+#[derive(Component)]
+pub struct SpectatorTarget;
+
+/// The player's own inputs are ignored while this is on it, they drive its spectator camera instead.
+#[derive(Component)]
+pub struct InputSuspended;
+
+/// Turns the camera of `player` into a spectator camera, or back into its follow camera.
+pub struct ToggleSpectator {
+    pub player: Entity,
+}
+
+/// On a `MainCamera` that no longer follows its player.
+/// Jump and dive fly up and down, dash flies faster and grab cycles through the targets.
+#[derive(Component)]
+pub struct SpectatorCamera {
+    pub mode: SpectatorMode,
+    yaw: f32,
+    pitch: f32,
+    distance: f32,
+    // Last frame's device inputs, for button edges
+    inputs: Inputs,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpectatorMode {
+    FreeFly,
+    // Orbits a `SpectatorTarget`
+    Follow(Entity),
+}
+
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .add_event::<ToggleSpectator>()
+        .add_system(toggle_spectator_system)
+        .add_system(spectator_camera_system.after(toggle_spectator_system).after(CharacterControllerLabel::Move));
+    }
+}
+
+fn toggle_spectator_system(
+    mut commands: Commands,
+    config: Res<CameraConfig>,
+    mut toggles: EventReader<ToggleSpectator>,
+    camera_query: Query<(Entity, &MainCamera, &Transform, Option<&SpectatorCamera>)>,
+) {
+    for ev in toggles.iter() {
+        for (entity, camera, transform, spectator) in camera_query.iter() {
+            if camera.player != ev.player {
+                continue;
+            }
+            if spectator.is_some() {
+                commands.entity(entity).remove::<SpectatorCamera>();
+                commands.entity(ev.player).remove::<InputSuspended>();
+                info!("Spectator camera off");
+            } else {
+                // Carry on looking the way the follow camera did
+                let forward = transform.forward();
+                commands.entity(entity).insert(SpectatorCamera {
+                    mode: SpectatorMode::FreeFly,
+                    yaw: (-forward.z).atan2(forward.x),
+                    pitch: (-forward.y).clamp(-1., 1.).asin(),
+                    distance: config.default_distance,
+                    inputs: Inputs::default(),
+                });
+                commands.entity(ev.player).insert(InputSuspended);
+                info!("Spectator camera on");
+            }
+        }
+    }
+}
+
+fn spectator_camera_system(
+    time: Res<Time>,
+    config: Res<CameraConfig>,
+    device_inputs: Res<DeviceInputs>,
+    slot_query: Query<&PlayerSlot>,
+    target_query: Query<(Entity, &GlobalTransform), With<SpectatorTarget>>,
+    mut camera_query: Query<(&MainCamera, &mut SpectatorCamera, &mut Transform), Without<CameraRailPlayback>>,
+) {
+    let dt = time.delta_seconds();
+    for (camera, mut spectator, mut transform) in camera_query.iter_mut() {
+        // The player's inputs are suspended, so read its device directly
+        let mut inputs = slot_query.get(camera.player).ok()
+            .and_then(|slot| device_inputs.0.get(&slot.device).cloned())
+            .unwrap_or_else(Inputs::default);
+        inputs.update_edges(&spectator.inputs);
+
+        spectator.yaw += inputs.camera_movement.x * config.yaw_speed * dt;
+        spectator.pitch = (spectator.pitch + inputs.camera_movement.y * config.pitch_speed * dt).clamp(-1.5, 1.5);
+
+        if inputs.just_pressed(Action::Grab) {
+            // Free fly, then every target in turn, then back to free fly
+            let mut targets: Vec<Entity> = target_query.iter().map(|(entity, _)| entity).collect();
+            targets.sort();
+            let next = match spectator.mode {
+                SpectatorMode::FreeFly => targets.first().copied(),
+                SpectatorMode::Follow(current) => {
+                    targets.iter().position(|target| *target == current).and_then(|i| targets.get(i + 1).copied())
+                }
+            };
+            spectator.mode = next.map_or(SpectatorMode::FreeFly, SpectatorMode::Follow);
+            info!("Spectating {:?}", spectator.mode);
+        }
+
+        let rotation = Quat::from_rotation_y(spectator.yaw);
+        let forward = rotation * Vec3::new(spectator.pitch.cos(), -spectator.pitch.sin(), 0.);
+        let target_position = match spectator.mode {
+            SpectatorMode::Follow(target) => target_query.get(target).ok().map(|(_, transform)| transform.translation()),
+            SpectatorMode::FreeFly => None,
+        };
+
+        if let Some(target_position) = target_position {
+            spectator.distance = (spectator.distance - inputs.camera_zoom * config.zoom_speed * dt).clamp(config.min_distance, config.max_distance);
+            transform.translation = target_position - forward * spectator.distance;
+            transform.look_at(target_position, Vec3::Y);
+        } else {
+            // Free fly, also when the followed target is gone
+            spectator.mode = SpectatorMode::FreeFly;
+
+            let right = rotation * Vec3::Z;
+            let mut velocity = forward * inputs.player_movement.y + right * inputs.player_movement.x;
+            if inputs.pressed(Action::Jump) {
+                velocity += Vec3::Y;
+            }
+            if inputs.pressed(Action::Dive) {
+                velocity -= Vec3::Y;
+            }
+            let speed = if inputs.pressed(Action::Dash) { FLY_SPEED * FAST_MULTIPLIER } else { FLY_SPEED };
+            transform.translation += velocity.clamp_length_max(1.) * speed * dt;
+            let look_target = transform.translation + forward;
+            transform.look_at(look_target, Vec3::Y);
+        }
+        spectator.inputs = inputs;
+    }
+}